@@ -20,12 +20,14 @@ fn main() {
         RawWindowHandle::AppKit(_) => {}
         RawWindowHandle::Orbital(_) => {}
         RawWindowHandle::Xlib(handle) => {
-            let display = Display::open(None);
-            let screen = Screen::default(&display);
-            let safex_window = unsafe { Window::from_raw(&display, &screen, handle.window) };
+            // event_loop.run never returns, so the display has to live for the whole program
+            let display: &'static Display = Box::leak(Box::new(Display::open(None).unwrap()));
+            let screen = Screen::default(display);
+            let safex_window =
+                unsafe { Window::from_raw(display, &screen, handle.window, BufferMode::Direct) };
             safex_window.set_window_title("Hello World from SafeX");
-            let cmap = ColorMap::default(&display, &screen);
-            let color = Color::from_rgb(&display, &cmap, 65535, 0, 65535);
+            let cmap = ColorMap::default(display, &screen);
+            let color = Color::from_rgb(display, &cmap, 65535, 0, 65535);
             event_loop.run(move |event, _, control_flow| {
                 control_flow.set_wait();
 
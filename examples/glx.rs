@@ -8,14 +8,14 @@ use std::{mem, ptr};
 static VERTEX_DATA: [GLfloat; 6] = [0.0, 0.5, 0.5, -0.5, -0.5, -0.5];
 
 // Shader sources
-static VS_SRC: &'static str = "
+static VS_SRC: &str = "
 #version 140
 in vec2 position;
 void main() {
     gl_Position = vec4(position, 0.0, 1.0);
 }";
 
-static FS_SRC: &'static str = "
+static FS_SRC: &str = "
 #version 140
 out vec4 out_color;
 void main() {
@@ -39,8 +39,7 @@ fn compile_shader(src: &str, ty: GLenum) -> GLuint {
         if status != (gl::TRUE as GLint) {
             let mut len = 0;
             gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
-            let mut buf = Vec::with_capacity(len as usize);
-            buf.set_len((len as usize) - 1); // subtract 1 to skip the trailing null character
+            let mut buf = vec![0u8; (len as usize) - 1]; // subtract 1 to skip the trailing null character
             gl::GetShaderInfoLog(
                 shader,
                 len,
@@ -49,9 +48,7 @@ fn compile_shader(src: &str, ty: GLenum) -> GLuint {
             );
             panic!(
                 "{}",
-                std::str::from_utf8(&buf)
-                    .ok()
-                    .expect("ShaderInfoLog not valid utf8")
+                std::str::from_utf8(&buf).expect("ShaderInfoLog not valid utf8")
             );
         }
     }
@@ -72,8 +69,7 @@ fn link_program(vs: GLuint, fs: GLuint) -> GLuint {
         if status != (gl::TRUE as GLint) {
             let mut len: GLint = 0;
             gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
-            let mut buf = Vec::with_capacity(len as usize);
-            buf.set_len((len as usize) - 1); // subtract 1 to skip the trailing null character
+            let mut buf = vec![0u8; (len as usize) - 1]; // subtract 1 to skip the trailing null character
             gl::GetProgramInfoLog(
                 program,
                 len,
@@ -82,9 +78,7 @@ fn link_program(vs: GLuint, fs: GLuint) -> GLuint {
             );
             panic!(
                 "{}",
-                std::str::from_utf8(&buf)
-                    .ok()
-                    .expect("ProgramInfoLog not valid utf8")
+                std::str::from_utf8(&buf).expect("ProgramInfoLog not valid utf8")
             );
         }
         program
@@ -92,17 +86,30 @@ fn link_program(vs: GLuint, fs: GLuint) -> GLuint {
 }
 
 fn main() {
-    let display = Display::open(None);
+    let display = Display::open(None).unwrap();
     let screen = Screen::default(&display);
     let vi = glx_choose_visual(
         &display,
         &mut [GLX_RGBA, GLX_DEPTH_SIZE, 24, GLX_DOUBLEBUFFER, GLX_NONE],
     )
     .unwrap();
-    let window =
-        Window::new_with_glx(&display, &screen, &vi, None, 0, 0, 200, 200, 1, 0, 0, &vi).unwrap();
+    let window = Window::new_with_glx(
+        &display,
+        &screen,
+        &vi,
+        BufferMode::Direct,
+        0,
+        0,
+        200,
+        200,
+        1,
+        0,
+        0,
+        &vi,
+    )
+    .unwrap();
 
-    let glc = GLXContext::create(&display, &vi, None, gl::TRUE as i32);
+    let glc = GLXContext::create(&display, &vi, None, gl::TRUE as i32).unwrap();
     glx_make_current(&display, &window, &glc);
 
     gl::load_with(|string| glc.get_proc_address(string).unwrap() as *mut c_void);
@@ -125,7 +132,7 @@ fn main() {
         gl::BufferData(
             gl::ARRAY_BUFFER,
             (VERTEX_DATA.len() * mem::size_of::<GLfloat>()) as GLsizeiptr,
-            mem::transmute(&VERTEX_DATA[0]),
+            VERTEX_DATA.as_ptr() as *const c_void,
             gl::STATIC_DRAW,
         );
 
@@ -148,8 +155,9 @@ fn main() {
 
     window.map();
 
-    window.run(|event, _control_flow| match event {
-        WindowEvent::Expose => {
+    let event_loop = EventLoop::new(&display);
+    event_loop.run(|window_id, event, control_flow| match event {
+        WindowEvent::Expose if window_id == Some(window.id()) => {
             unsafe {
                 gl::Viewport(0, 0, 100, 100);
                 gl::ClearColor(0.3, 0.3, 0.3, 1.0);
@@ -159,5 +167,7 @@ fn main() {
             }
             window.glx_swap_buffers();
         }
+        WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+        _ => {}
     })
 }
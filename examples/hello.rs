@@ -1,7 +1,7 @@
 use safex::xlib::*;
 
 fn main() {
-    let display = Display::open(None);
+    let display = Display::open(None).unwrap();
     let screen = Screen::default(&display);
     let root = Window::root_window(&display, &screen);
 
@@ -14,8 +14,8 @@ fn main() {
     let window = Window::create_simple(
         &display,
         &screen,
-        Some(()),
-        Some(root),
+        BufferMode::PixmapBackBuffer,
+        Some(&root),
         0,
         0,
         500,
@@ -23,7 +23,8 @@ fn main() {
         1,
         0,
         white,
-    );
+    )
+    .unwrap();
 
     window.set_window_title("Hello World");
 
@@ -66,8 +67,9 @@ fn main() {
     };
 
     window.map();
-    window.run(|event, _control_flow| match event {
-        WindowEvent::Expose => {
+    let event_loop = EventLoop::new(&display);
+    event_loop.run(|window_id, event, control_flow| match event {
+        WindowEvent::Expose if window_id == Some(window.id()) => {
             window.set_window_background(white);
             window.fill_rectangle(rect);
             window.fill_arc(arc);
@@ -76,5 +78,7 @@ fn main() {
             window.draw_string("Hello World", 10, 240, black);
             window.copy_to_buffer();
         }
+        WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+        _ => {}
     })
 }
@@ -0,0 +1,96 @@
+use crate::xlib::{AsRaw, Display};
+use std::ffi::{c_int, c_uchar, c_ulong};
+
+type Status = c_int;
+type XdbeBackBuffer = c_ulong;
+
+#[repr(C)]
+struct XdbeSwapInfo {
+    swap_window: c_ulong,
+    swap_action: c_uchar,
+}
+
+// x11-rs has no bindings for the Double Buffer Extension
+#[link(name = "Xext")]
+extern "C" {
+    fn XdbeQueryExtension(
+        display: *mut x11::xlib::Display,
+        major: *mut c_int,
+        minor: *mut c_int,
+    ) -> Status;
+    fn XdbeAllocateBackBufferName(
+        display: *mut x11::xlib::Display,
+        window: c_ulong,
+        swap_action: c_uchar,
+    ) -> XdbeBackBuffer;
+    fn XdbeDeallocateBackBufferName(
+        display: *mut x11::xlib::Display,
+        buffer: XdbeBackBuffer,
+    ) -> Status;
+    fn XdbeSwapBuffers(
+        display: *mut x11::xlib::Display,
+        swap_info: *mut XdbeSwapInfo,
+        num_windows: c_int,
+    ) -> Status;
+}
+
+/// What the back buffer holds after a swap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SwapAction {
+    /// Whatever is cheapest for the server.
+    Undefined,
+    /// Cleared to the window background.
+    Background,
+    /// The previous front buffer.
+    Untouched,
+    /// A copy of what was just swapped to the front, like a pixmap back buffer.
+    #[default]
+    Copied,
+}
+
+impl AsRaw<c_uchar> for SwapAction {
+    fn as_raw(&self) -> c_uchar {
+        match self {
+            SwapAction::Undefined => 0,
+            SwapAction::Background => 1,
+            SwapAction::Untouched => 2,
+            SwapAction::Copied => 3,
+        }
+    }
+}
+
+/// Returns the DBE version if the server supports the extension.
+pub fn query_version(display: &Display) -> Option<(i32, i32)> {
+    _query_version(display.as_raw())
+}
+
+pub(crate) fn _query_version(display: *mut x11::xlib::Display) -> Option<(i32, i32)> {
+    let (mut major, mut minor) = (0, 0);
+    let status = unsafe { XdbeQueryExtension(display, &mut major, &mut minor) };
+    (status != 0).then_some((major, minor))
+}
+
+// Fails with BadMatch if the window's visual does not support double buffering
+pub(crate) unsafe fn _allocate_back_buffer(
+    display: *mut x11::xlib::Display,
+    window: c_ulong,
+    swap_action: SwapAction,
+) -> c_ulong {
+    XdbeAllocateBackBufferName(display, window, swap_action.as_raw())
+}
+
+pub(crate) unsafe fn _deallocate_back_buffer(display: *mut x11::xlib::Display, buffer: c_ulong) {
+    XdbeDeallocateBackBufferName(display, buffer);
+}
+
+pub(crate) unsafe fn _swap_buffers(
+    display: *mut x11::xlib::Display,
+    window: c_ulong,
+    swap_action: SwapAction,
+) {
+    let mut swap_info = XdbeSwapInfo {
+        swap_window: window,
+        swap_action: swap_action.as_raw(),
+    };
+    XdbeSwapBuffers(display, &mut swap_info, 1);
+}
@@ -1,9 +1,10 @@
 use crate::xlib::{
-    AsRaw, Display, Mask, Screen, Visual, VisualInfo, Window, WindowClass,
+    AsRaw, BadAlloc, Display, Mask, Screen, Visual, VisualInfo, WindowClass, WindowRef, XError,
 };
 use std::ffi::{c_int, c_uchar, CString};
+use std::marker::PhantomData;
 
-use std::ptr::addr_of_mut;
+use std::ptr::{addr_of_mut, null_mut};
 use x11::glx::*;
 use x11::xlib::*;
 
@@ -70,12 +71,21 @@ export!(GLX_NONE, GLXAttribute);
 
 export!(GLX_RGBA, GLXAttribute);
 
-pub struct GLXContext {
+/// A GLX rendering context, destroyed on drop.
+pub struct GLXContext<'d> {
     glc: x11::glx::GLXContext,
+    display: *mut x11::xlib::Display,
+    _display: PhantomData<&'d Display>,
 }
 
-impl GLXContext {
-    pub fn create(display: &Display, vi: &VisualInfo, _glc: Option<GLXContext>, flag: i32) -> Self {
+impl<'d> GLXContext<'d> {
+    /// Creates a context that shares display lists and textures with `share_list`, if given.
+    pub fn create(
+        display: &'d Display,
+        vi: &VisualInfo,
+        share_list: Option<&GLXContext>,
+        flag: i32,
+    ) -> Result<Self, XError> {
         let mut vi = XVisualInfo {
             visual: vi.visual.as_raw(),
             visualid: vi.visualid as VisualID,
@@ -89,15 +99,32 @@ impl GLXContext {
             bits_per_rgb: vi.bits_per_rgb,
         };
 
-        let glc = unsafe {
+        let glc = display.checked(|| unsafe {
             glXCreateContext(
                 display.as_raw(),
                 addr_of_mut!(vi),
-                std::ptr::null_mut(),
+                share_list.map_or(null_mut(), |share_list| share_list.glc),
                 flag as c_int,
             )
-        };
-        Self { glc }
+        })?;
+
+        // Client side failures return NULL without raising a protocol error
+        if glc.is_null() {
+            return Err(XError {
+                error_code: BadAlloc,
+                request_code: 0,
+                minor_code: 0,
+                resource_id: 0,
+                serial: 0,
+                description: String::from("glXCreateContext returned NULL"),
+            });
+        }
+
+        Ok(Self {
+            glc,
+            display: display.as_raw(),
+            _display: PhantomData,
+        })
     }
 
     pub fn get_proc_address(&self, string: &str) -> Option<unsafe extern "C" fn()> {
@@ -105,16 +132,25 @@ impl GLXContext {
     }
 }
 
-impl AsRaw<x11::glx::GLXContext> for GLXContext {
+impl Drop for GLXContext<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            glXDestroyContext(self.display, self.glc);
+        }
+    }
+}
+
+impl AsRaw<x11::glx::GLXContext> for GLXContext<'_> {
     fn as_raw(&self) -> x11::glx::GLXContext {
         self.glc
     }
 }
 
+#[allow(clippy::result_unit_err)]
 pub fn glx_choose_visual(display: &Display, attrs: &mut [GLXAttribute]) -> Result<VisualInfo, ()> {
     let vi = unsafe {
         let vi = glXChooseVisual(display.as_raw(), 0, attrs.as_mut_ptr());
-        if vi == std::ptr::null_mut() {
+        if vi.is_null() {
             return Err(());
         }
         vi
@@ -138,7 +174,7 @@ pub fn glx_choose_visual(display: &Display, attrs: &mut [GLXAttribute]) -> Resul
     Ok(vi)
 }
 
-pub fn glx_make_current(display: &Display, window: &Window, glx: &GLXContext) {
+pub fn glx_make_current(display: &Display, window: &WindowRef, glx: &GLXContext) {
     unsafe {
         glXMakeCurrent(display.as_raw(), window.as_raw(), glx.as_raw());
     }
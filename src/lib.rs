@@ -1,3 +1,5 @@
+#[cfg(feature = "dbe")]
+pub mod dbe;
 #[cfg(feature = "glx")]
 pub mod glx;
 #[cfg(feature = "xlib_xcb")]
@@ -10,12 +12,3 @@ pub mod xinput;
 pub mod xkb;
 #[cfg(feature = "xlib")]
 pub mod xlib;
-
-pub(crate) mod util {
-    use std::ffi::{c_char, CString};
-
-    pub fn str_to_c_char(str: &str) -> *const c_char {
-        let cstring = CString::new(str).unwrap();
-        cstring.as_ptr()
-    }
-}
@@ -3,6 +3,12 @@ use x11::xlib_xcb::*;
 
 pub struct XCBConnection(*mut xcb_connection_t);
 
+impl AsRaw<*mut xcb_connection_t> for XCBConnection {
+    fn as_raw(&self) -> *mut xcb_connection_t {
+        self.0
+    }
+}
+
 pub enum EventQueueOwner {
     XLibOwnsEventQueue = 0,
     XCBOwnsEventQueue = 1,
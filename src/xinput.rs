@@ -1 +1,517 @@
+use crate::xlib::{AsRaw, Atom, BadValue, Display, WindowEvent, XError};
+use std::ffi::{c_int, CStr};
+use std::sync::Mutex;
+use x11::xinput2::{
+    XIAllDevices, XIAnyClassInfo, XIButtonClass, XIButtonClassInfo, XIDeviceChange,
+    XIDeviceChangedEvent, XIDeviceDisabled, XIDeviceEnabled, XIDeviceInfo, XIDirectTouch,
+    XIEventMask, XIFloatingSlave, XIFreeDeviceInfo, XIHierarchyEvent, XIHierarchyInfo, XIKeyClass,
+    XIKeyClassInfo, XIMasterAdded, XIMasterKeyboard, XIMasterPointer, XIMasterRemoved,
+    XIModeAbsolute, XIQueryDevice, XIQueryVersion, XIScrollClass, XIScrollClassInfo,
+    XIScrollTypeVertical, XISelectEvents, XISetMask, XISlaveAdded, XISlaveAttached,
+    XISlaveDetached, XISlaveKeyboard, XISlavePointer, XISlaveRemoved, XITouchClass,
+    XITouchClassInfo, XIValuatorClass, XIValuatorClassInfo, XI_DeviceChanged, XI_HierarchyChanged,
+};
+use x11::xlib::{
+    GenericEvent, XDefaultRootWindow, XEvent, XFreeEventData, XGetEventData, XQueryExtension,
+};
 
+// (display, major opcode) for displays that went through `query_version`
+static OPCODES: Mutex<Vec<(usize, c_int)>> = Mutex::new(Vec::new());
+
+/// Negotiates XInput 2.2 with the server and returns the version it agreed to, or `None`
+/// if XInput 2 is not available. If another library already negotiated a newer version on
+/// the connection, that version is returned.
+///
+/// Call this before [`select_hierarchy_events`]; safex only recognizes XInput events on
+/// displays it has been called for.
+pub fn query_version(display: &Display) -> Option<(i32, i32)> {
+    let (mut opcode, mut event, mut error) = (0, 0, 0);
+    let present = unsafe {
+        XQueryExtension(
+            display.as_raw(),
+            c"XInputExtension".as_ptr(),
+            &mut opcode,
+            &mut event,
+            &mut error,
+        )
+    };
+    if present == 0 {
+        return None;
+    }
+
+    let (mut major, mut minor) = (2, 2);
+    let status = match display
+        .checked(|| unsafe { XIQueryVersion(display.as_raw(), &mut major, &mut minor) })
+    {
+        Ok(status) => status,
+        // Someone already negotiated a newer version on this connection, which the server
+        // won't go back on; asking for at least as much reports it
+        Err(error) if error.error_code == BadValue => {
+            (major, minor) = (2, u16::MAX as c_int);
+            display
+                .checked(|| unsafe { XIQueryVersion(display.as_raw(), &mut major, &mut minor) })
+                .ok()?
+        }
+        Err(_) => return None,
+    };
+    // BadRequest means the server only has XInput 1
+    if status != 0 {
+        return None;
+    }
+
+    let mut opcodes = OPCODES.lock().unwrap();
+    opcodes.retain(|(opcode_display, _)| *opcode_display != display.as_raw() as usize);
+    opcodes.push((display.as_raw() as usize, opcode));
+    Some((major, minor))
+}
+
+pub(crate) fn _forget_display(display: usize) {
+    OPCODES
+        .lock()
+        .unwrap()
+        .retain(|(opcode_display, _)| *opcode_display != display);
+}
+
+/// What a device is in the XInput 2 hierarchy. Master devices are the cursors and keyboard
+/// foci the user sees; the physical slave devices drive them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceUse {
+    MasterPointer,
+    MasterKeyboard,
+    SlavePointer,
+    SlaveKeyboard,
+    /// A slave not attached to any master, which only sends events to clients that ask.
+    FloatingSlave,
+}
+
+impl DeviceUse {
+    #[allow(non_upper_case_globals)]
+    fn from_raw(raw: c_int) -> Option<Self> {
+        match raw {
+            XIMasterPointer => Some(DeviceUse::MasterPointer),
+            XIMasterKeyboard => Some(DeviceUse::MasterKeyboard),
+            XISlavePointer => Some(DeviceUse::SlavePointer),
+            XISlaveKeyboard => Some(DeviceUse::SlaveKeyboard),
+            XIFloatingSlave => Some(DeviceUse::FloatingSlave),
+            _ => None,
+        }
+    }
+}
+
+/// An input capability of a device.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceClass {
+    Key {
+        num_keycodes: u32,
+    },
+    /// One label per button, `Atom::NONE` where the driver gave none.
+    Button {
+        labels: Vec<Atom>,
+    },
+    /// An axis such as "Rel X", "Abs Pressure" or "Abs MT Position X".
+    Valuator {
+        number: u32,
+        label: Atom,
+        min: f64,
+        max: f64,
+        /// Units per meter, or 0 if unknown.
+        resolution: u32,
+        absolute: bool,
+    },
+    /// Marks valuator `number` as a smooth scrolling axis.
+    Scroll {
+        number: u32,
+        vertical: bool,
+        /// The valuator delta that equals one legacy wheel click.
+        increment: f64,
+    },
+    /// `direct` touch devices are touchscreens; the others are touchpads.
+    Touch {
+        direct: bool,
+        num_touches: u32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceInfo {
+    pub id: i32,
+    pub name: String,
+    pub device_use: DeviceUse,
+    /// The paired master for master devices, the master for attached slaves, 0 for floating ones.
+    pub attachment: i32,
+    pub enabled: bool,
+    pub classes: Vec<DeviceClass>,
+}
+
+/// Lists every master and slave device.
+pub fn query_devices(display: &Display) -> Vec<DeviceInfo> {
+    let mut count = 0;
+    unsafe {
+        let devices = XIQueryDevice(display.as_raw(), XIAllDevices, &mut count);
+        if devices.is_null() {
+            return Vec::new();
+        }
+
+        let infos = std::slice::from_raw_parts(devices, count.max(0) as usize)
+            .iter()
+            .filter_map(|device| _device_info(device))
+            .collect();
+        XIFreeDeviceInfo(devices);
+        infos
+    }
+}
+
+/// One device's entry in a [`WindowEvent::DeviceHierarchyChanged`] event, with its state
+/// after the change.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HierarchyChange {
+    pub device: i32,
+    pub device_use: Option<DeviceUse>,
+    pub attachment: i32,
+    pub enabled: bool,
+    pub added: bool,
+    pub removed: bool,
+    /// The device was attached to a master or detached from one.
+    pub attachment_changed: bool,
+    pub enabled_changed: bool,
+}
+
+/// Asks for device hot-plugging and capability changes, which the event loop delivers as
+/// [`WindowEvent::DeviceHierarchyChanged`] and [`WindowEvent::DeviceChanged`].
+pub fn select_hierarchy_events(display: &Display) -> Result<(), XError> {
+    let mut mask = [0; 2];
+    XISetMask(&mut mask, XI_HierarchyChanged);
+    XISetMask(&mut mask, XI_DeviceChanged);
+
+    let mut event_mask = XIEventMask {
+        deviceid: XIAllDevices,
+        mask_len: mask.len() as c_int,
+        mask: mask.as_mut_ptr(),
+    };
+    display.checked(|| unsafe {
+        // Hierarchy events are only reported on the root window
+        let root = XDefaultRootWindow(display.as_raw());
+        XISelectEvents(display.as_raw(), root, &mut event_mask, 1);
+    })
+}
+
+unsafe fn _device_info(device: &XIDeviceInfo) -> Option<DeviceInfo> {
+    Some(DeviceInfo {
+        id: device.deviceid,
+        name: if device.name.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(device.name).to_string_lossy().into_owned()
+        },
+        device_use: DeviceUse::from_raw(device._use)?,
+        attachment: device.attachment,
+        enabled: device.enabled != 0,
+        classes: _device_classes(device.classes, device.num_classes),
+    })
+}
+
+#[allow(non_upper_case_globals)]
+unsafe fn _device_classes(classes: *mut *mut XIAnyClassInfo, count: c_int) -> Vec<DeviceClass> {
+    if classes.is_null() {
+        return Vec::new();
+    }
+
+    std::slice::from_raw_parts(classes, count.max(0) as usize)
+        .iter()
+        .filter_map(|&class| match (*class)._type {
+            XIKeyClass => {
+                let class = &*(class as *const XIKeyClassInfo);
+                Some(DeviceClass::Key {
+                    num_keycodes: class.num_keycodes as u32,
+                })
+            }
+            XIButtonClass => {
+                let class = &*(class as *const XIButtonClassInfo);
+                let labels = if class.labels.is_null() {
+                    vec![Atom::NONE; class.num_buttons.max(0) as usize]
+                } else {
+                    std::slice::from_raw_parts(class.labels, class.num_buttons.max(0) as usize)
+                        .iter()
+                        .map(|&label| Atom::from_raw(label))
+                        .collect()
+                };
+                Some(DeviceClass::Button { labels })
+            }
+            XIValuatorClass => {
+                let class = &*(class as *const XIValuatorClassInfo);
+                Some(DeviceClass::Valuator {
+                    number: class.number as u32,
+                    label: Atom::from_raw(class.label),
+                    min: class.min,
+                    max: class.max,
+                    resolution: class.resolution as u32,
+                    absolute: class.mode == XIModeAbsolute,
+                })
+            }
+            XIScrollClass => {
+                let class = &*(class as *const XIScrollClassInfo);
+                Some(DeviceClass::Scroll {
+                    number: class.number as u32,
+                    vertical: class.scroll_type == XIScrollTypeVertical,
+                    increment: class.increment,
+                })
+            }
+            XITouchClass => {
+                let class = &*(class as *const XITouchClassInfo);
+                Some(DeviceClass::Touch {
+                    direct: class.mode == XIDirectTouch,
+                    num_touches: class.num_touches as u32,
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn _hierarchy_change(info: &XIHierarchyInfo) -> HierarchyChange {
+    HierarchyChange {
+        device: info.deviceid,
+        device_use: DeviceUse::from_raw(info._use),
+        attachment: info.attachment,
+        enabled: info.enabled != 0,
+        added: info.flags & (XIMasterAdded | XISlaveAdded) != 0,
+        removed: info.flags & (XIMasterRemoved | XISlaveRemoved) != 0,
+        attachment_changed: info.flags & (XISlaveAttached | XISlaveDetached) != 0,
+        enabled_changed: info.flags & (XIDeviceEnabled | XIDeviceDisabled) != 0,
+    }
+}
+
+// Turns XInput 2 events into window events; they aren't tied to a window
+#[allow(non_upper_case_globals)]
+pub(crate) fn _translate_event(
+    display: *mut x11::xlib::Display,
+    event: &mut XEvent,
+) -> Option<WindowEvent> {
+    if event.get_type() != GenericEvent {
+        return None;
+    }
+    let opcode = OPCODES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(opcode_display, _)| *opcode_display == display as usize)?
+        .1;
+
+    unsafe {
+        let cookie = &mut event.generic_event_cookie;
+        if cookie.extension != opcode || XGetEventData(display, cookie) == 0 {
+            return None;
+        }
+
+        let translated = match cookie.evtype {
+            XI_HierarchyChanged => {
+                let hierarchy = &*(cookie.data as *const XIHierarchyEvent);
+                let info = if hierarchy.info.is_null() {
+                    &[][..]
+                } else {
+                    std::slice::from_raw_parts(hierarchy.info, hierarchy.num_info.max(0) as usize)
+                };
+                let changes = info
+                    .iter()
+                    // Every device is listed; only the ones with flags changed
+                    .filter(|info| info.flags != 0)
+                    .map(_hierarchy_change)
+                    .collect();
+                Some(WindowEvent::DeviceHierarchyChanged(changes))
+            }
+            XI_DeviceChanged => {
+                let changed = &*(cookie.data as *const XIDeviceChangedEvent);
+                Some(WindowEvent::DeviceChanged {
+                    device: changed.deviceid,
+                    source: changed.sourceid,
+                    slave_switch: changed.reason != XIDeviceChange,
+                    classes: _device_classes(changed.classes, changed.num_classes),
+                })
+            }
+            _ => None,
+        };
+
+        XFreeEventData(display, cookie);
+        translated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr::null_mut;
+    use x11::xinput2::{XIButtonState, XIDependentTouch, XIModeRelative, XIScrollTypeHorizontal};
+
+    #[test]
+    fn device_use_from_raw() {
+        assert_eq!(
+            DeviceUse::from_raw(XIMasterPointer),
+            Some(DeviceUse::MasterPointer)
+        );
+        assert_eq!(
+            DeviceUse::from_raw(XIMasterKeyboard),
+            Some(DeviceUse::MasterKeyboard)
+        );
+        assert_eq!(
+            DeviceUse::from_raw(XISlavePointer),
+            Some(DeviceUse::SlavePointer)
+        );
+        assert_eq!(
+            DeviceUse::from_raw(XISlaveKeyboard),
+            Some(DeviceUse::SlaveKeyboard)
+        );
+        assert_eq!(
+            DeviceUse::from_raw(XIFloatingSlave),
+            Some(DeviceUse::FloatingSlave)
+        );
+        assert_eq!(DeviceUse::from_raw(0), None);
+        assert_eq!(DeviceUse::from_raw(6), None);
+    }
+
+    #[test]
+    fn hierarchy_change_flags() {
+        let change = _hierarchy_change(&XIHierarchyInfo {
+            deviceid: 12,
+            attachment: 2,
+            _use: XISlavePointer,
+            enabled: 1,
+            flags: XISlaveAdded | XIDeviceEnabled,
+        });
+        assert_eq!(
+            change,
+            HierarchyChange {
+                device: 12,
+                device_use: Some(DeviceUse::SlavePointer),
+                attachment: 2,
+                enabled: true,
+                added: true,
+                removed: false,
+                attachment_changed: false,
+                enabled_changed: true,
+            }
+        );
+
+        let change = _hierarchy_change(&XIHierarchyInfo {
+            deviceid: 12,
+            attachment: 0,
+            _use: XIFloatingSlave,
+            enabled: 0,
+            flags: XISlaveDetached | XIDeviceDisabled,
+        });
+        assert!(change.attachment_changed && change.enabled_changed);
+        assert!(!change.added && !change.removed && !change.enabled);
+
+        let change = _hierarchy_change(&XIHierarchyInfo {
+            deviceid: 3,
+            attachment: 2,
+            _use: XIMasterKeyboard,
+            enabled: 0,
+            flags: XIMasterRemoved,
+        });
+        assert!(change.removed && !change.added);
+        assert!(!change.attachment_changed && !change.enabled_changed);
+    }
+
+    #[test]
+    fn device_classes() {
+        let mut labels = [7, 0, 9];
+        let mut key = XIKeyClassInfo {
+            _type: XIKeyClass,
+            sourceid: 10,
+            num_keycodes: 248,
+            keycodes: null_mut(),
+        };
+        let mut button = XIButtonClassInfo {
+            _type: XIButtonClass,
+            sourceid: 10,
+            num_buttons: labels.len() as c_int,
+            labels: labels.as_mut_ptr(),
+            state: XIButtonState {
+                mask_len: 0,
+                mask: null_mut(),
+            },
+        };
+        let mut unlabelled = XIButtonClassInfo {
+            _type: XIButtonClass,
+            sourceid: 10,
+            num_buttons: 2,
+            labels: null_mut(),
+            state: XIButtonState {
+                mask_len: 0,
+                mask: null_mut(),
+            },
+        };
+        let mut valuator = XIValuatorClassInfo {
+            _type: XIValuatorClass,
+            sourceid: 10,
+            number: 1,
+            label: 42,
+            min: -1.0,
+            max: 1.0,
+            value: 0.5,
+            resolution: 1000,
+            mode: XIModeRelative,
+        };
+        let mut scroll = XIScrollClassInfo {
+            _type: XIScrollClass,
+            sourceid: 10,
+            number: 2,
+            scroll_type: XIScrollTypeHorizontal,
+            increment: 15.0,
+            flags: 0,
+        };
+        let mut touch = XITouchClassInfo {
+            _type: XITouchClass,
+            sourceid: 10,
+            mode: XIDependentTouch,
+            num_touches: 5,
+        };
+        // An unknown class is skipped
+        let mut unknown = XIAnyClassInfo {
+            _type: 99,
+            sourceid: 10,
+        };
+
+        let mut classes = [
+            &mut key as *mut _ as *mut XIAnyClassInfo,
+            &mut button as *mut _ as *mut XIAnyClassInfo,
+            &mut unlabelled as *mut _ as *mut XIAnyClassInfo,
+            &mut valuator as *mut _ as *mut XIAnyClassInfo,
+            &mut scroll as *mut _ as *mut XIAnyClassInfo,
+            &mut touch as *mut _ as *mut XIAnyClassInfo,
+            &mut unknown,
+        ];
+        let decoded = unsafe { _device_classes(classes.as_mut_ptr(), classes.len() as c_int) };
+        assert_eq!(
+            decoded,
+            [
+                DeviceClass::Key { num_keycodes: 248 },
+                DeviceClass::Button {
+                    labels: vec![Atom::from_raw(7), Atom::NONE, Atom::from_raw(9)],
+                },
+                DeviceClass::Button {
+                    labels: vec![Atom::NONE; 2],
+                },
+                DeviceClass::Valuator {
+                    number: 1,
+                    label: Atom::from_raw(42),
+                    min: -1.0,
+                    max: 1.0,
+                    resolution: 1000,
+                    absolute: false,
+                },
+                DeviceClass::Scroll {
+                    number: 2,
+                    vertical: false,
+                    increment: 15.0,
+                },
+                DeviceClass::Touch {
+                    direct: false,
+                    num_touches: 5,
+                },
+            ]
+        );
+
+        assert!(unsafe { _device_classes(null_mut(), 3) }.is_empty());
+    }
+}
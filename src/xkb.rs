@@ -1 +1,590 @@
+use crate::xlib::{AsRaw, Atom, Display, Modifiers, WindowEvent, WindowRef};
+use std::ffi::{c_int, c_uchar, c_uint, c_ulong, c_ushort, CStr, CString};
+use std::sync::Mutex;
+use x11::xlib::{
+    KeySym, XEvent, XFreeModifiermap, XGetModifierMapping, XKeysymToKeycode, XKeysymToString,
+    XStringToKeysym, XkbAnyEvent, XkbBell, XkbDescPtr, XkbFreeKeyboard, XkbGetAutoRepeatRate,
+    XkbGetDetectableAutoRepeat, XkbGetIndicatorState, XkbGetMap, XkbGetNamedIndicator, XkbGetNames,
+    XkbGetState, XkbGroupStateMask, XkbMapNotify, XkbMapNotifyEvent, XkbMapNotifyMask,
+    XkbNewKeyboardNotify, XkbNewKeyboardNotifyMask, XkbNumKbdGroups, XkbQueryExtension,
+    XkbRefreshKeyboardMapping, XkbSelectEventDetails, XkbSelectEvents, XkbSetAutoRepeatRate,
+    XkbSetDetectableAutoRepeat, XkbSetNamedIndicator, XkbStateNotify, XkbStateNotifyEvent,
+    XkbStateRec,
+};
 
+mod unicode;
+
+// XKBlib.h values x11-rs doesn't export
+const XKB_USE_CORE_KBD: c_uint = 0x0100;
+const XKB_MAJOR_VERSION: c_int = 1;
+const XKB_MINOR_VERSION: c_int = 0;
+const XKB_KEY_TYPES_MASK: c_uint = 1 << 0;
+const XKB_KEY_SYMS_MASK: c_uint = 1 << 1;
+const XKB_SYMBOLS_NAME_MASK: c_uint = 1 << 2;
+const XKB_GROUP_NAMES_MASK: c_uint = 1 << 12;
+
+// The client map layout from XKBstr.h; x11-rs declares it as an opaque struct
+#[repr(C)]
+struct XkbClientMapRec {
+    size_types: c_uchar,
+    num_types: c_uchar,
+    types: *mut XkbKeyTypeRec,
+    size_syms: c_ushort,
+    num_syms: c_ushort,
+    syms: *mut KeySym,
+    key_sym_map: *mut XkbSymMapRec,
+    modmap: *mut c_uchar,
+}
+
+#[repr(C)]
+struct XkbKeyTypeRec {
+    mods_mask: c_uchar,
+    mods_real_mods: c_uchar,
+    mods_vmods: c_ushort,
+    num_levels: c_uchar,
+    map_count: c_uchar,
+    map: *mut u8,
+    preserve: *mut u8,
+    name: c_ulong,
+    level_names: *mut c_ulong,
+}
+
+#[repr(C)]
+struct XkbSymMapRec {
+    kt_index: [c_uchar; XkbNumKbdGroups],
+    group_info: c_uchar,
+    width: c_uchar,
+    offset: c_ushort,
+}
+
+// (display, first event code) for displays that went through `query_extension`
+static EVENT_BASES: Mutex<Vec<(usize, c_int)>> = Mutex::new(Vec::new());
+
+/// Initializes XKB for the display and returns the server's extension version.
+///
+/// Call this before [`select_events`]; safex only recognizes XKB events on displays it
+/// has been called for.
+pub fn query_extension(display: &Display) -> Option<(i32, i32)> {
+    let (mut opcode, mut event_base, mut error_base) = (0, 0, 0);
+    let (mut major, mut minor) = (XKB_MAJOR_VERSION, XKB_MINOR_VERSION);
+    let supported = unsafe {
+        XkbQueryExtension(
+            display.as_raw(),
+            &mut opcode,
+            &mut event_base,
+            &mut error_base,
+            &mut major,
+            &mut minor,
+        )
+    };
+    if supported == 0 {
+        return None;
+    }
+
+    let mut bases = EVENT_BASES.lock().unwrap();
+    bases.retain(|(base_display, _)| *base_display != display.as_raw() as usize);
+    bases.push((display.as_raw() as usize, event_base));
+    Some((major, minor))
+}
+
+pub(crate) fn _forget_display(display: usize) {
+    EVENT_BASES
+        .lock()
+        .unwrap()
+        .retain(|(base_display, _)| *base_display != display);
+}
+
+/// Asks the server for layout switches and keymap changes, which the event loop delivers as
+/// [`WindowEvent::KeyboardGroupChanged`] and [`WindowEvent::KeymapChanged`].
+pub fn select_events(display: &Display) -> bool {
+    unsafe {
+        let selected = XkbSelectEvents(
+            display.as_raw(),
+            XKB_USE_CORE_KBD,
+            XkbNewKeyboardNotifyMask | XkbMapNotifyMask,
+            XkbNewKeyboardNotifyMask | XkbMapNotifyMask,
+        ) != 0;
+
+        // Modifier changes would otherwise send a state event on every Shift press
+        selected
+            && XkbSelectEventDetails(
+                display.as_raw(),
+                XKB_USE_CORE_KBD,
+                XkbStateNotify as c_uint,
+                XkbGroupStateMask,
+                XkbGroupStateMask,
+            ) != 0
+    }
+}
+
+/// The group (layout index) currently in effect on the core keyboard.
+pub fn active_group(display: &Display) -> Option<u32> {
+    let mut state: XkbStateRec = unsafe { std::mem::zeroed() };
+    let status = unsafe { XkbGetState(display.as_raw(), XKB_USE_CORE_KBD, &mut state) };
+    (status == 0).then_some(state.group as u32)
+}
+
+/// Makes a held key send only repeated presses, followed by one release when it actually
+/// goes up, instead of a release before every repeat. Returns whether the server supports it.
+///
+/// The setting belongs to this client's connection and ends with it.
+pub fn set_detectable_auto_repeat(display: &Display, detectable: bool) -> bool {
+    let mut supported = 0;
+    unsafe {
+        XkbSetDetectableAutoRepeat(display.as_raw(), detectable as c_int, &mut supported);
+    }
+    supported != 0
+}
+
+pub fn detectable_auto_repeat(display: &Display) -> bool {
+    let mut supported = 0;
+    let detectable = unsafe { XkbGetDetectableAutoRepeat(display.as_raw(), &mut supported) };
+    supported != 0 && detectable != 0
+}
+
+/// Key repeat timing, in milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepeatRate {
+    /// How long a key is held before it starts repeating.
+    pub delay: u32,
+    /// The time between repeats.
+    pub interval: u32,
+}
+
+pub fn repeat_rate(display: &Display) -> Option<RepeatRate> {
+    let (mut delay, mut interval) = (0, 0);
+    let status = unsafe {
+        XkbGetAutoRepeatRate(
+            display.as_raw(),
+            XKB_USE_CORE_KBD,
+            &mut delay,
+            &mut interval,
+        )
+    };
+    (status != 0).then_some(RepeatRate { delay, interval })
+}
+
+/// Changes the repeat timing of the core keyboard for every client on the server.
+pub fn set_repeat_rate(display: &Display, rate: RepeatRate) -> bool {
+    unsafe {
+        XkbSetAutoRepeatRate(
+            display.as_raw(),
+            XKB_USE_CORE_KBD,
+            rate.delay,
+            rate.interval,
+        ) != 0
+    }
+}
+
+/// Keyboard indicator LEDs, looked up by their standard XKB names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Led {
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    Compose,
+    Kana,
+}
+
+impl Led {
+    fn atom_name(&self) -> &'static str {
+        match self {
+            Led::CapsLock => "Caps Lock",
+            Led::NumLock => "Num Lock",
+            Led::ScrollLock => "Scroll Lock",
+            Led::Compose => "Compose",
+            Led::Kana => "Kana",
+        }
+    }
+}
+
+/// Whether the LED is lit, or `None` if the keymap has no such indicator.
+pub fn led_state(display: &Display, led: Led) -> Option<bool> {
+    let atom = display.intern_atom(led.atom_name(), true)?;
+    let (mut index, mut state, mut real) = (0, 0, 0);
+    let found = unsafe {
+        XkbGetNamedIndicator(
+            display.as_raw(),
+            atom.as_raw(),
+            &mut index,
+            &mut state,
+            std::ptr::null_mut(),
+            &mut real,
+        )
+    };
+    (found != 0).then_some(state != 0)
+}
+
+/// Lights or clears the LED. For lock LEDs this also toggles the lock itself, since the
+/// indicators are driven by the modifier state.
+pub fn set_led(display: &Display, led: Led, on: bool) -> bool {
+    let Some(atom) = display.intern_atom(led.atom_name(), true) else {
+        return false;
+    };
+
+    unsafe {
+        XkbSetNamedIndicator(
+            display.as_raw(),
+            atom.as_raw(),
+            1,
+            on as c_int,
+            0,
+            std::ptr::null_mut(),
+        ) != 0
+    }
+}
+
+/// The state of all 32 indicators as a bitmask, bit `n` being indicator `n`.
+pub fn indicator_state(display: &Display) -> Option<u32> {
+    let mut state = 0;
+    let status = unsafe { XkbGetIndicatorState(display.as_raw(), XKB_USE_CORE_KBD, &mut state) };
+    (status == 0).then_some(state)
+}
+
+/// Rings the bell for `window`. `percent` is relative to the base volume, from -100 to 100.
+///
+/// `name` identifies the kind of bell, such as "terminal-bell", so that a desktop's bell
+/// handler can play a matching sound or flash the window instead of beeping.
+pub fn bell(display: &Display, window: &WindowRef, percent: i32, name: Option<&str>) -> bool {
+    let name = match name {
+        Some(name) => match display.intern_atom(name, false) {
+            Some(atom) => atom,
+            None => return false,
+        },
+        None => Atom::NONE,
+    };
+
+    unsafe {
+        XkbBell(
+            display.as_raw(),
+            window.as_raw(),
+            percent.clamp(-100, 100),
+            name.as_raw(),
+        ) != 0
+    }
+}
+
+/// A snapshot of the core keyboard's keymap. It does not follow later changes; fetch a new
+/// one on [`WindowEvent::KeymapChanged`].
+pub struct Keymap<'d> {
+    display: &'d Display,
+    desc: XkbDescPtr,
+}
+
+impl<'d> Keymap<'d> {
+    pub fn get(display: &'d Display) -> Option<Self> {
+        unsafe {
+            let desc = XkbGetMap(
+                display.as_raw(),
+                XKB_KEY_TYPES_MASK | XKB_KEY_SYMS_MASK,
+                XKB_USE_CORE_KBD,
+            );
+            if desc.is_null() {
+                return None;
+            }
+
+            let keymap = Self { display, desc };
+            // Without names the keymap is still usable for keysym lookups
+            XkbGetNames(
+                display.as_raw(),
+                XKB_SYMBOLS_NAME_MASK | XKB_GROUP_NAMES_MASK,
+                desc,
+            );
+            Some(keymap)
+        }
+    }
+
+    pub fn min_keycode(&self) -> u32 {
+        unsafe { (*self.desc).min_key_code as u32 }
+    }
+
+    pub fn max_keycode(&self) -> u32 {
+        unsafe { (*self.desc).max_key_code as u32 }
+    }
+
+    /// How many groups the key has keysyms for.
+    pub fn num_groups(&self, keycode: u32) -> u32 {
+        self.sym_map(keycode)
+            .map_or(0, |sym_map| (sym_map.group_info & 0x0f) as u32)
+    }
+
+    /// How many shift levels the key has in `group`.
+    pub fn num_levels(&self, keycode: u32, group: u32) -> u32 {
+        let Some(sym_map) = self.sym_map(keycode) else {
+            return 0;
+        };
+        if group >= (sym_map.group_info & 0x0f) as u32 {
+            return 0;
+        }
+
+        unsafe {
+            let map = (*self.desc).map as *const XkbClientMapRec;
+            let key_type = (*map).types.add(sym_map.kt_index[group as usize] as usize);
+            (*key_type).num_levels as u32
+        }
+    }
+
+    /// The keysym at `level` of `group`, or `None` if the key has no such position.
+    pub fn keysym(&self, keycode: u32, group: u32, level: u32) -> Option<KeySym> {
+        if level >= self.num_levels(keycode, group) {
+            return None;
+        }
+
+        let sym_map = self.sym_map(keycode)?;
+        let index = sym_map.offset as usize + (group * sym_map.width as u32 + level) as usize;
+        let keysym = unsafe {
+            let map = (*self.desc).map as *const XkbClientMapRec;
+            *(*map).syms.add(index)
+        };
+        (keysym != 0).then_some(keysym)
+    }
+
+    /// Human readable layout names, such as "English (US)", indexed by group.
+    pub fn group_names(&self) -> Vec<String> {
+        let names = unsafe { (*self.desc).names };
+        if names.is_null() {
+            return Vec::new();
+        }
+
+        unsafe { (*names).groups }
+            .iter()
+            .take_while(|&&atom| atom != 0)
+            .filter_map(|&atom| self.display.atom_name(Atom::from_raw(atom)))
+            .collect()
+    }
+
+    /// The symbols component the keymap was built from, for example "pc+us+jp:2+inet(evdev)".
+    /// The `:n` suffixes tie layouts to groups, which makes short names like "jp" easy to
+    /// pick out for an indicator.
+    pub fn symbols_name(&self) -> Option<String> {
+        let names = unsafe { (*self.desc).names };
+        if names.is_null() {
+            return None;
+        }
+
+        match unsafe { (*names).symbols } {
+            0 => None,
+            atom => self.display.atom_name(Atom::from_raw(atom)),
+        }
+    }
+
+    fn sym_map(&self, keycode: u32) -> Option<&XkbSymMapRec> {
+        if keycode < self.min_keycode() || keycode > self.max_keycode() {
+            return None;
+        }
+
+        unsafe {
+            let map = (*self.desc).map as *const XkbClientMapRec;
+            if map.is_null() || (*map).key_sym_map.is_null() {
+                return None;
+            }
+            Some(&*(*map).key_sym_map.add(keycode as usize))
+        }
+    }
+}
+
+impl Drop for Keymap<'_> {
+    fn drop(&mut self) {
+        unsafe { XkbFreeKeyboard(self.desc, 0, 1) };
+    }
+}
+
+impl AsRaw<XkbDescPtr> for Keymap<'_> {
+    fn as_raw(&self) -> XkbDescPtr {
+        self.desc
+    }
+}
+
+/// Parses a keysym name as used in keysymdef.h without the `XK_` prefix, such as "Return",
+/// "a" or "F1". Names of the form "U20AC" and "0x1008ff13" are accepted too.
+pub fn string_to_keysym(name: &str) -> Option<KeySym> {
+    let name = CString::new(name).ok()?;
+    match unsafe { XStringToKeysym(name.as_ptr()) } {
+        0 => None,
+        keysym => Some(keysym),
+    }
+}
+
+/// The name of a keysym, the inverse of [`string_to_keysym`].
+pub fn keysym_to_string(keysym: KeySym) -> Option<String> {
+    // The string is static and must not be freed
+    let name = unsafe { XKeysymToString(keysym) };
+    if name.is_null() {
+        return None;
+    }
+    Some(
+        unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned(),
+    )
+}
+
+/// The character a keysym types, covering Latin-1, the legacy keysym sets (Latin-2 to 9,
+/// Greek, Cyrillic, Hebrew, Arabic, Thai, Korean, ...), Unicode keysyms and the keypad.
+/// Function keys like Return and BackSpace give their control characters.
+pub fn keysym_to_char(keysym: KeySym) -> Option<char> {
+    let code_point = match keysym {
+        0x20..=0x7e | 0xa0..=0xff => keysym as u32,
+        0x0100_0100..=0x0110_ffff => (keysym - 0x0100_0000) as u32,
+        // BackSpace, Tab, Linefeed, Clear, Return and Escape
+        0xff08..=0xff0b | 0xff0d | 0xff1b => (keysym & 0x7f) as u32,
+        0xffff => 0x7f,
+        0xff80 => 0x20,
+        // KP_Tab, KP_Enter, then KP_Multiply to KP_9 and KP_Equal
+        0xff89 | 0xff8d | 0xffaa..=0xffb9 | 0xffbd => (keysym & 0x7f) as u32,
+        0x0100..=0xffff => {
+            let index = unicode::KEYSYM_TO_UNICODE
+                .binary_search_by_key(&(keysym as u16), |&(keysym, _)| keysym)
+                .ok()?;
+            unicode::KEYSYM_TO_UNICODE[index].1 as u32
+        }
+        _ => return None,
+    };
+    char::from_u32(code_point)
+}
+
+/// A keycode that produces `keysym` in the current keyboard mapping, in any group or level.
+pub fn keysym_to_keycode(display: &Display, keysym: KeySym) -> Option<u32> {
+    match unsafe { XKeysymToKeycode(display.as_raw(), keysym) } {
+        0 => None,
+        keycode => Some(keycode as u32),
+    }
+}
+
+/// Which keycodes set each of the eight core modifiers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModifierMapping {
+    // Indexed like the state bits: Shift, Lock, Control, Mod1 to Mod5
+    keycodes: [Vec<u32>; 8],
+}
+
+impl ModifierMapping {
+    pub fn get(display: &Display) -> Option<Self> {
+        unsafe {
+            let map = XGetModifierMapping(display.as_raw());
+            if map.is_null() {
+                return None;
+            }
+
+            let per_modifier = (*map).max_keypermod.max(0) as usize;
+            let keycodes = std::slice::from_raw_parts((*map).modifiermap, per_modifier * 8);
+            let mut mapping = Self::default();
+            for (modifier, keys) in keycodes.chunks(per_modifier.max(1)).enumerate().take(8) {
+                // Unused slots are zero
+                mapping.keycodes[modifier] = keys
+                    .iter()
+                    .filter(|&&keycode| keycode != 0)
+                    .map(|&keycode| keycode as u32)
+                    .collect();
+            }
+
+            XFreeModifiermap(map);
+            Some(mapping)
+        }
+    }
+
+    /// The keycodes bound to a single modifier, such as `Modifiers::ALT` for Mod1.
+    pub fn keycodes(&self, modifier: Modifiers) -> &[u32] {
+        let bits = modifier.bits();
+        if !bits.is_power_of_two() || bits >= 1 << 8 {
+            return &[];
+        }
+        &self.keycodes[bits.trailing_zeros() as usize]
+    }
+
+    /// The modifiers pressing `keycode` sets.
+    pub fn modifiers(&self, keycode: u32) -> Modifiers {
+        let bits = self
+            .keycodes
+            .iter()
+            .enumerate()
+            .filter(|(_, keys)| keys.contains(&keycode))
+            .fold(0, |bits, (modifier, _)| bits | 1 << modifier);
+        Modifiers::from_state(bits)
+    }
+}
+
+// Turns XKB events into window events; they aren't tied to a window
+#[allow(non_upper_case_globals)]
+pub(crate) fn _translate_event(
+    display: *mut x11::xlib::Display,
+    event: &mut XEvent,
+) -> Option<WindowEvent> {
+    let event_base = EVENT_BASES
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(base_display, _)| *base_display == display as usize)?
+        .1;
+    if event.get_type() != event_base {
+        return None;
+    }
+
+    unsafe {
+        let any = &*(event as *mut XEvent as *const XkbAnyEvent);
+        match any.xkb_type {
+            XkbStateNotify => {
+                let state = &*(event as *mut XEvent as *const XkbStateNotifyEvent);
+                Some(WindowEvent::KeyboardGroupChanged(state.group as u32))
+            }
+            XkbMapNotify => {
+                // Keeps XLookupString and friends in step with the new map
+                XkbRefreshKeyboardMapping(event as *mut XEvent as *mut XkbMapNotifyEvent);
+                Some(WindowEvent::KeymapChanged)
+            }
+            XkbNewKeyboardNotify => Some(WindowEvent::KeymapChanged),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11::keysym::*;
+
+    #[test]
+    fn keysym_to_char_table_is_sorted() {
+        // keysym_to_char binary searches it
+        assert!(unicode::KEYSYM_TO_UNICODE
+            .windows(2)
+            .all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn keysym_to_char() {
+        let char_of = |keysym: c_uint| super::keysym_to_char(keysym as KeySym);
+
+        // Latin-1 and Unicode keysyms map directly
+        assert_eq!(char_of(XK_a), Some('a'));
+        assert_eq!(char_of(XK_eacute), Some('\u{e9}'));
+        // EuroSign, which x11-rs leaves out
+        assert_eq!(super::keysym_to_char(0x20ac), Some('\u{20ac}'));
+        assert_eq!(super::keysym_to_char(0x0100_20ac), Some('\u{20ac}'));
+        assert_eq!(super::keysym_to_char(0x0101_f600), Some('\u{1f600}'));
+        assert_eq!(super::keysym_to_char(0x0100_d800), None);
+
+        // Legacy sets go through the table
+        assert_eq!(char_of(XK_Aogonek), Some('\u{104}'));
+        assert_eq!(char_of(XK_Greek_alpha), Some('\u{3b1}'));
+        assert_eq!(char_of(XK_Cyrillic_a), Some('\u{430}'));
+        assert_eq!(char_of(XK_hebrew_aleph), Some('\u{5d0}'));
+
+        // Function and keypad keys
+        assert_eq!(char_of(XK_BackSpace), Some('\u{8}'));
+        assert_eq!(char_of(XK_Tab), Some('\t'));
+        assert_eq!(char_of(XK_Return), Some('\r'));
+        assert_eq!(char_of(XK_Escape), Some('\u{1b}'));
+        assert_eq!(char_of(XK_Delete), Some('\u{7f}'));
+        assert_eq!(char_of(XK_KP_Space), Some(' '));
+        assert_eq!(char_of(XK_KP_Enter), Some('\r'));
+        assert_eq!(char_of(XK_KP_Multiply), Some('*'));
+        assert_eq!(char_of(XK_KP_5), Some('5'));
+        assert_eq!(char_of(XK_KP_Equal), Some('='));
+
+        assert_eq!(char_of(XK_Home), None);
+        assert_eq!(char_of(XK_F1), None);
+        assert_eq!(char_of(XK_Shift_L), None);
+        assert_eq!(super::keysym_to_char(0), None);
+    }
+}
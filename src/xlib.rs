@@ -1,18 +1,33 @@
-use crate::util::str_to_c_char;
-use std::ffi::{c_char, c_int, c_long, c_uint, c_ulong, c_ushort, CStr, CString};
+#[cfg(feature = "dbe")]
+use crate::dbe::{self, SwapAction};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_int, c_long, c_uchar, c_uint, c_ulong, c_ushort, c_void, CStr, CString};
+use std::fmt;
+use std::marker::PhantomData;
 use std::mem::MaybeUninit;
-use std::ptr::{addr_of, null_mut};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::ops::{BitAnd, BitOr, Deref};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::ptr::{addr_of, null, null_mut};
+use std::sync::{Mutex, Once};
+use std::time::{Duration, Instant};
+#[cfg(feature = "glx")]
 use x11::glx::glXSwapBuffers;
 use x11::xlib::*;
 
 type Buffer = c_ulong;
 
+pub type KeySym = c_ulong;
 pub type Mask = c_ulong;
 pub type VisualID = u64;
 
 // Re-Exports
 macro_rules! export {
     ($cons:ident,$type_:ident) => {
+        // Keeps Xlib's name so code ported from C reads the same
+        #[allow(non_upper_case_globals)]
         pub const $cons: $type_ = x11::xlib::$cons as $type_;
     };
 }
@@ -50,6 +65,42 @@ export!(PropertyChangeMask, Mask);
 export!(ColormapChangeMask, Mask);
 export!(OwnerGrabButtonMask, Mask);
 
+// Selected on windows that are created without an explicit event mask
+const DEFAULT_EVENT_MASK: Mask = ExposureMask
+    | KeyPressMask
+    | KeyReleaseMask
+    | ButtonPressMask
+    | ButtonReleaseMask
+    | PointerMotionMask
+    | EnterWindowMask
+    | LeaveWindowMask
+    | StructureNotifyMask
+    | FocusChangeMask
+    | PropertyChangeMask
+    | VisibilityChangeMask;
+
+// From Xutil.h, which x11-rs does not export
+const NORMAL_STATE: c_int = 1;
+const ICONIC_STATE: c_int = 3;
+
+const MWM_HINTS_FUNCTIONS: u32 = 1 << 0;
+const MWM_HINTS_DECORATIONS: u32 = 1 << 1;
+
+const MWM_FUNC_ALL: u32 = 1 << 0;
+const MWM_FUNC_RESIZE: u32 = 1 << 1;
+const MWM_FUNC_MOVE: u32 = 1 << 2;
+const MWM_FUNC_MINIMIZE: u32 = 1 << 3;
+const MWM_FUNC_MAXIMIZE: u32 = 1 << 4;
+const MWM_FUNC_CLOSE: u32 = 1 << 5;
+
+const MWM_DECOR_ALL: u32 = 1 << 0;
+const MWM_DECOR_BORDER: u32 = 1 << 1;
+const MWM_DECOR_RESIZEH: u32 = 1 << 2;
+const MWM_DECOR_TITLE: u32 = 1 << 3;
+const MWM_DECOR_MENU: u32 = 1 << 4;
+const MWM_DECOR_MINIMIZE: u32 = 1 << 5;
+const MWM_DECOR_MAXIMIZE: u32 = 1 << 6;
+
 pub type WindowClass = c_uint;
 
 export!(InputOutput, WindowClass);
@@ -72,10 +123,58 @@ export!(CWDontPropagate, WindowAttribute);
 export!(CWColormap, WindowAttribute);
 export!(CWCursor, WindowAttribute);
 
+pub type Gravity = c_int;
+
+export!(ForgetGravity, Gravity);
+export!(NorthWestGravity, Gravity);
+export!(NorthGravity, Gravity);
+export!(NorthEastGravity, Gravity);
+export!(WestGravity, Gravity);
+export!(CenterGravity, Gravity);
+export!(EastGravity, Gravity);
+export!(SouthWestGravity, Gravity);
+export!(SouthGravity, Gravity);
+export!(SouthEastGravity, Gravity);
+export!(StaticGravity, Gravity);
+
+pub type ErrorCode = u8;
+
+export!(Success, ErrorCode);
+export!(BadRequest, ErrorCode);
+export!(BadValue, ErrorCode);
+export!(BadWindow, ErrorCode);
+export!(BadPixmap, ErrorCode);
+export!(BadAtom, ErrorCode);
+export!(BadCursor, ErrorCode);
+export!(BadFont, ErrorCode);
+export!(BadMatch, ErrorCode);
+export!(BadDrawable, ErrorCode);
+export!(BadAccess, ErrorCode);
+export!(BadAlloc, ErrorCode);
+export!(BadColor, ErrorCode);
+export!(BadGC, ErrorCode);
+export!(BadIDChoice, ErrorCode);
+export!(BadName, ErrorCode);
+export!(BadLength, ErrorCode);
+export!(BadImplementation, ErrorCode);
+
 pub trait AsRaw<T> {
     fn as_raw(&self) -> T;
 }
 
+/// Where a [`Window`]'s drawing calls end up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferMode {
+    /// Draw straight onto the window.
+    Direct,
+    /// Draw onto a pixmap that [`Window::copy_to_buffer`] copies to the window. The pixmap is
+    /// reallocated when the window is resized.
+    PixmapBackBuffer,
+    /// Use the Double Buffer Extension when built with the `dbe` feature and the window's
+    /// visual supports it, and `PixmapBackBuffer` otherwise.
+    Dbe,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Arc {
     pub x: u32,
@@ -87,8 +186,76 @@ pub struct Arc {
     pub pixel: Pixel,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Atom(c_ulong);
+
+impl Atom {
+    pub const NONE: Atom = Atom(0);
+    pub const PRIMARY: Atom = Atom(XA_PRIMARY);
+    pub const SECONDARY: Atom = Atom(XA_SECONDARY);
+    pub const ATOM: Atom = Atom(XA_ATOM);
+    pub const CARDINAL: Atom = Atom(XA_CARDINAL);
+    pub const INTEGER: Atom = Atom(XA_INTEGER);
+    pub const PIXMAP: Atom = Atom(XA_PIXMAP);
+    pub const STRING: Atom = Atom(XA_STRING);
+    pub const WINDOW: Atom = Atom(XA_WINDOW);
+    pub const WM_CLASS: Atom = Atom(XA_WM_CLASS);
+    pub const WM_CLIENT_MACHINE: Atom = Atom(XA_WM_CLIENT_MACHINE);
+    pub const WM_HINTS: Atom = Atom(XA_WM_HINTS);
+    pub const WM_ICON_NAME: Atom = Atom(XA_WM_ICON_NAME);
+    pub const WM_NAME: Atom = Atom(XA_WM_NAME);
+    pub const WM_NORMAL_HINTS: Atom = Atom(XA_WM_NORMAL_HINTS);
+    pub const WM_TRANSIENT_FOR: Atom = Atom(XA_WM_TRANSIENT_FOR);
+
+    pub fn from_raw(atom: c_ulong) -> Self {
+        Self(atom)
+    }
+}
+
+impl AsRaw<c_ulong> for Atom {
+    fn as_raw(&self) -> c_ulong {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ButtonEvent {
+    pub button: u32,
+    pub x: i32,
+    pub y: i32,
+    pub x_root: i32,
+    pub y_root: i32,
+    pub state: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClassHint {
+    pub instance: String,
+    pub class: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientMessageEvent {
+    pub message_type: Atom,
+    pub data: ClientMessageData,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClientMessageData {
+    Byte([u8; 20]),
+    Short([i16; 10]),
+    Long([c_long; 5]),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ControlFlow {
+    /// Block until the next event arrives.
     Wait,
+    /// Block until the next event arrives or the deadline passes, whichever comes first.
+    WaitUntil(Instant),
+    /// Never block; `AboutToWait` is delivered again as soon as the queue is drained.
+    Poll,
+    /// Return from the event loop.
     Exit,
 }
 
@@ -104,7 +271,7 @@ pub struct Color<'a> {
 
 impl<'a> Color<'a> {
     pub fn from_rgb(display: &Display, cmap: &ColorMap, r: u16, g: u16, b: u16) -> Self {
-        let mut color: XColor = unsafe { MaybeUninit::uninit().assume_init() };
+        let mut color: XColor = unsafe { std::mem::zeroed() };
 
         color.red = r as c_ushort;
         color.green = g as c_ushort;
@@ -116,9 +283,9 @@ impl<'a> Color<'a> {
 
         Self {
             pixel: Pixel { pixel: color.pixel },
-            red: color.red as u16,
-            green: color.green as u16,
-            blue: color.blue as u16,
+            red: color.red,
+            green: color.green,
+            blue: color.blue,
             flags: unsafe { CStr::from_ptr(addr_of!(color.flags)).to_str().unwrap() },
             pad: unsafe { CStr::from_ptr(addr_of!(color.pad)).to_str().unwrap() },
         }
@@ -129,32 +296,61 @@ impl<'a> Color<'a> {
     }
 }
 
-pub struct ColorMap {
+/// A colormap. Ones made with [`ColorMap::create`] are freed on drop; the default colormap
+/// belongs to the screen.
+pub struct ColorMap<'d> {
     cmap: c_ulong,
+    display: *mut x11::xlib::Display,
+    owned: bool,
+    _display: PhantomData<&'d Display>,
 }
 
-impl ColorMap {
-    pub fn create(display: &Display, window: &Window, visual: &Visual) -> Self {
-        let cmap = unsafe {
+impl<'d> ColorMap<'d> {
+    pub fn create(
+        display: &'d Display,
+        window: &WindowRef,
+        visual: &Visual,
+    ) -> Result<Self, XError> {
+        let cmap = display.checked(|| unsafe {
             XCreateColormap(
                 display.as_raw(),
                 window.as_raw(),
                 visual.as_raw(),
                 AllocNone,
             )
-        };
+        })?;
 
-        Self { cmap }
+        Ok(Self {
+            cmap,
+            display: display.display,
+            owned: true,
+            _display: PhantomData,
+        })
     }
 
-    pub fn default(display: &Display, screen: &Screen) -> Self {
+    pub fn default(display: &'d Display, screen: &Screen) -> Self {
         let cmap =
             unsafe { XDefaultColormap(display.display, XScreenNumberOfScreen(screen.screen)) };
-        Self { cmap }
+        Self {
+            cmap,
+            display: display.display,
+            owned: false,
+            _display: PhantomData,
+        }
+    }
+}
+
+impl Drop for ColorMap<'_> {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe {
+                XFreeColormap(self.display, self.cmap);
+            }
+        }
     }
 }
 
-impl AsRaw<c_ulong> for ColorMap {
+impl AsRaw<c_ulong> for ColorMap<'_> {
     fn as_raw(&self) -> c_ulong {
         self.cmap
     }
@@ -165,13 +361,33 @@ pub struct Display {
 }
 
 impl Display {
-    pub fn open(display_name: Option<&str>) -> Self {
-        let display = match display_name {
-            None => unsafe { XOpenDisplay(null_mut()) },
-            Some(display_name) => unsafe { XOpenDisplay(str_to_c_char(display_name)) },
+    pub fn open(display_name: Option<&str>) -> Result<Self, DisplayError> {
+        let name = match display_name {
+            None => std::env::var("DISPLAY").unwrap_or_default(),
+            Some(display_name) => display_name.to_string(),
         };
 
-        Self { display }
+        let c_name = match display_name {
+            None => None,
+            Some(display_name) => Some(
+                CString::new(display_name)
+                    .map_err(|_| DisplayError::BadName(display_name.to_string()))?,
+            ),
+        };
+
+        let display = unsafe {
+            XOpenDisplay(match &c_name {
+                None => null(),
+                Some(c_name) => c_name.as_ptr(),
+            })
+        };
+
+        if display.is_null() {
+            return Err(_diagnose_open_failure(&name));
+        }
+
+        _install_error_handlers();
+        Ok(Self { display })
     }
 
     pub fn string(&self) -> String {
@@ -208,17 +424,162 @@ impl Display {
         }
     }
 
-    pub fn from_raw(display: *mut x11::xlib::Display) -> Self {
-        Self { display }
+    pub fn intern_atom(&self, name: &str, only_if_exists: bool) -> Option<Atom> {
+        _intern_atom(self.display, name, only_if_exists)
+    }
+
+    /// Interns every name in a single round trip. Names containing a NUL byte map to `Atom::NONE`.
+    pub fn intern_atoms(&self, names: &[&str]) -> Vec<Atom> {
+        let display = self.display as usize;
+        let mut atoms: Vec<Atom> = {
+            let cache = ATOM_CACHE.lock().unwrap();
+            names
+                .iter()
+                .map(|name| {
+                    let key = (display, name.to_string());
+                    cache.atoms.get(&key).copied().unwrap_or(Atom::NONE)
+                })
+                .collect()
+        };
+
+        let missing: Vec<(usize, CString)> = names
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| atoms[*i] == Atom::NONE)
+            .filter_map(|(i, name)| CString::new(*name).ok().map(|name| (i, name)))
+            .collect();
+        if missing.is_empty() {
+            return atoms;
+        }
+
+        let mut c_names: Vec<*mut c_char> = missing
+            .iter()
+            .map(|(_, name)| name.as_ptr() as *mut c_char)
+            .collect();
+        let mut interned = vec![0; missing.len()];
+        unsafe {
+            XInternAtoms(
+                self.display,
+                c_names.as_mut_ptr(),
+                c_names.len() as c_int,
+                False,
+                interned.as_mut_ptr(),
+            );
+        }
+
+        let mut cache = ATOM_CACHE.lock().unwrap();
+        for ((i, _), atom) in missing.into_iter().zip(interned) {
+            if atom != 0 {
+                atoms[i] = Atom(atom);
+                cache.insert((display, names[i].to_string()), Atom(atom));
+            }
+        }
+
+        atoms
+    }
+
+    /// Looks up the keysym at `level` (0 unshifted, 1 shifted, ...) of `group` for a keycode.
+    pub fn keycode_to_keysym(&self, keycode: u32, group: u32, level: u32) -> KeySym {
+        unsafe {
+            XkbKeycodeToKeysym(
+                self.display,
+                keycode as c_uchar,
+                group as c_int,
+                level as c_int,
+            )
+        }
+    }
+
+    pub fn atom_name(&self, atom: Atom) -> Option<String> {
+        let key = (self.display as usize, atom);
+        if let Some(name) = ATOM_CACHE.lock().unwrap().names.get(&key) {
+            return Some(name.clone());
+        }
+
+        let name = self
+            .checked(|| unsafe { XGetAtomName(self.display, atom.0) })
+            .ok()?;
+        if name.is_null() {
+            return None;
+        }
+
+        let string = unsafe {
+            let string = CStr::from_ptr(name).to_string_lossy().into_owned();
+            XFree(name as *mut _);
+            string
+        };
+
+        ATOM_CACHE
+            .lock()
+            .unwrap()
+            .insert((key.0, string.clone()), atom);
+        Some(string)
+    }
+
+    pub fn sync(&self) {
+        unsafe {
+            XSync(self.display, False);
+        }
+    }
+
+    /// Runs `func` and collects every protocol error it caused instead of reporting them.
+    ///
+    /// The display is synced before returning, so all errors from requests made inside
+    /// `func` have arrived by the time this returns.
+    pub fn trap_errors<F, R>(&self, func: F) -> (R, Vec<XError>)
+    where
+        F: FnOnce() -> R,
+    {
+        _trap_errors(self.display, func)
+    }
+
+    pub(crate) fn checked<F, R>(&self, func: F) -> Result<R, XError>
+    where
+        F: FnOnce() -> R,
+    {
+        _checked(self.display, func)
+    }
+
+    /// # Safety
+    /// `display` must be an open connection. It is closed when the returned `Display` is dropped.
+    pub unsafe fn from_raw(display: *mut x11::xlib::Display) -> Result<Self, DisplayError> {
+        if display.is_null() {
+            return Err(DisplayError::NullHandle);
+        }
+
+        _install_error_handlers();
+        Ok(Self { display })
+    }
+
+    /// Sets what happens to protocol errors that no [`Display::trap_errors`] call catches, on
+    /// every display. By default they are printed to stderr.
+    ///
+    /// The handler runs inside Xlib, so it must not make requests of its own.
+    pub fn set_error_handler<F>(handler: F)
+    where
+        F: Fn(&XError) + Send + 'static,
+    {
+        *ERROR_HANDLER.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    /// Sets what happens when the connection to a server is lost, on every display. The
+    /// handler gets the display name. Xlib exits the process as soon as it returns.
+    pub fn set_io_error_handler<F>(handler: F)
+    where
+        F: Fn(&str) + Send + 'static,
+    {
+        *IO_ERROR_HANDLER.lock().unwrap() = Some(Box::new(handler));
     }
 }
 
 impl Drop for Display {
     fn drop(&mut self) {
-        if !self.display.is_null() {
-            unsafe {
-                XCloseDisplay(self.display);
-            }
+        unsafe {
+            _close_input_method(self.display);
+        }
+        _forget_display(self.display);
+        unsafe {
+            XCloseDisplay(self.display);
         }
     }
 }
@@ -229,6 +590,73 @@ impl AsRaw<*mut x11::xlib::Display> for Display {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisplayError {
+    /// The display name could not be parsed, or no name was given and `$DISPLAY` is unset.
+    BadName(String),
+    /// Nothing is listening on the socket the display name refers to.
+    ConnectionRefused(String),
+    /// The server accepted the connection but rejected our credentials.
+    AuthorizationFailed(String),
+    /// A null pointer was passed to `Display::from_raw`.
+    NullHandle,
+}
+
+impl fmt::Display for DisplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisplayError::BadName(name) => write!(f, "invalid display name \"{}\"", name),
+            DisplayError::ConnectionRefused(name) => {
+                write!(f, "could not connect to display \"{}\"", name)
+            }
+            DisplayError::AuthorizationFailed(name) => {
+                write!(f, "authorization failed for display \"{}\"", name)
+            }
+            DisplayError::NullHandle => write!(f, "display handle is null"),
+        }
+    }
+}
+
+impl std::error::Error for DisplayError {}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CrossingEvent {
+    pub x: i32,
+    pub y: i32,
+    pub x_root: i32,
+    pub y_root: i32,
+    pub state: u32,
+}
+
+pub struct EventLoop<'a> {
+    display: &'a Display,
+}
+
+impl<'a> EventLoop<'a> {
+    pub fn new(display: &'a Display) -> Self {
+        Self { display }
+    }
+
+    /// Runs the event loop until `func` sets [`ControlFlow::Exit`]. Each event comes with the
+    /// window it is for, or `None` for events that are not tied to a window, such as
+    /// `AboutToWait`.
+    pub fn run<F>(&self, func: F)
+    where
+        F: FnMut(Option<WindowId>, WindowEvent, &mut ControlFlow),
+    {
+        _run_event_loop(self.display.display, func);
+    }
+}
+
+/// The WM frame size around a window, from `_NET_FRAME_EXTENTS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameExtents {
+    pub left: u32,
+    pub right: u32,
+    pub top: u32,
+    pub bottom: u32,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Geometry {
     pub x: i32,
@@ -239,15 +667,314 @@ pub struct Geometry {
     pub depth: u32,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct GC(x11::xlib::GC);
+/// A graphics context. Ones made with [`GC::create`] are freed on drop; the default GC
+/// belongs to the screen.
+#[derive(Debug, PartialEq)]
+pub struct GC<'d> {
+    gc: x11::xlib::GC,
+    display: *mut x11::xlib::Display,
+    owned: bool,
+    _display: PhantomData<&'d Display>,
+}
+
+impl<'d> GC<'d> {
+    pub fn create(display: &'d Display, drawable: &WindowRef) -> Result<Self, XError> {
+        let gc = display.checked(|| unsafe {
+            XCreateGC(display.display, drawable.drawable(), 0, null_mut())
+        })?;
+        Ok(Self {
+            gc,
+            display: display.display,
+            owned: true,
+            _display: PhantomData,
+        })
+    }
+
+    pub fn default(display: &'d Display, screen: &Screen) -> Self {
+        unsafe { GC::borrowed(display.display, XScreenNumberOfScreen(screen.screen)) }
+    }
+
+    unsafe fn borrowed(display: *mut x11::xlib::Display, screen: c_int) -> Self {
+        Self {
+            gc: XDefaultGC(display, screen),
+            display,
+            owned: false,
+            _display: PhantomData,
+        }
+    }
+}
 
-impl GC {}
+impl Drop for GC<'_> {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe {
+                XFreeGC(self.display, self.gc);
+            }
+        }
+    }
+}
 
-impl AsRaw<x11::xlib::GC> for GC {
+impl AsRaw<x11::xlib::GC> for GC<'_> {
     fn as_raw(&self) -> x11::xlib::GC {
+        self.gc
+    }
+}
+
+/// An RGBA image, 4 bytes per pixel in row-major order, for use as a window icon.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Icon {
+    width: u32,
+    height: u32,
+    rgba: Vec<u8>,
+}
+
+impl Icon {
+    /// Returns `None` if `rgba` does not hold exactly `width * height` pixels.
+    pub fn from_rgba(width: u32, height: u32, rgba: Vec<u8>) -> Option<Self> {
+        if width == 0 || height == 0 || rgba.len() != width as usize * height as usize * 4 {
+            return None;
+        }
+        Some(Self {
+            width,
+            height,
+            rgba,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn rgba(&self) -> &[u8] {
+        &self.rgba
+    }
+
+    fn pixels(&self) -> impl Iterator<Item = [u8; 4]> + '_ {
+        self.rgba
+            .chunks_exact(4)
+            .map(|pixel| [pixel[0], pixel[1], pixel[2], pixel[3]])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitialState {
+    Normal,
+    Iconic,
+}
+
+/// A key, named where X has a dedicated keysym for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Character(char),
+    Escape,
+    Enter,
+    Tab,
+    Backspace,
+    Delete,
+    Insert,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Left,
+    Right,
+    Up,
+    Down,
+    F(u8),
+    Shift,
+    Control,
+    Alt,
+    Super,
+    AltGr,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    PrintScreen,
+    Pause,
+    Menu,
+    Unidentified(KeySym),
+}
+
+impl Key {
+    #[allow(non_upper_case_globals)]
+    pub fn from_keysym(keysym: KeySym) -> Self {
+        use x11::keysym::*;
+
+        match keysym as c_uint {
+            XK_Escape => Key::Escape,
+            XK_Return | XK_KP_Enter => Key::Enter,
+            XK_Tab | XK_KP_Tab | XK_ISO_Left_Tab => Key::Tab,
+            XK_BackSpace => Key::Backspace,
+            XK_Delete | XK_KP_Delete => Key::Delete,
+            XK_Insert | XK_KP_Insert => Key::Insert,
+            XK_Home | XK_KP_Home => Key::Home,
+            XK_End | XK_KP_End => Key::End,
+            XK_Page_Up | XK_KP_Page_Up => Key::PageUp,
+            XK_Page_Down | XK_KP_Page_Down => Key::PageDown,
+            XK_Left | XK_KP_Left => Key::Left,
+            XK_Right | XK_KP_Right => Key::Right,
+            XK_Up | XK_KP_Up => Key::Up,
+            XK_Down | XK_KP_Down => Key::Down,
+            XK_F1..=XK_F35 => Key::F((keysym as c_uint - XK_F1 + 1) as u8),
+            XK_Shift_L | XK_Shift_R => Key::Shift,
+            XK_Control_L | XK_Control_R => Key::Control,
+            XK_Alt_L | XK_Alt_R | XK_Meta_L | XK_Meta_R => Key::Alt,
+            XK_Super_L | XK_Super_R => Key::Super,
+            XK_ISO_Level3_Shift | XK_Mode_switch => Key::AltGr,
+            XK_Caps_Lock => Key::CapsLock,
+            XK_Num_Lock => Key::NumLock,
+            XK_Scroll_Lock => Key::ScrollLock,
+            XK_Print => Key::PrintScreen,
+            XK_Pause => Key::Pause,
+            XK_Menu => Key::Menu,
+            XK_KP_Space => Key::Character(' '),
+            _ => _keysym_to_char(keysym).map_or(Key::Unidentified(keysym), Key::Character),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub keycode: u32,
+    pub keysym: KeySym,
+    pub key: Key,
+    pub state: u32,
+    pub modifiers: Modifiers,
+    /// The text the key produced, composed by the input method if the window has one
+    /// (see [`WindowRef::enable_input_method`]). Only set on key presses.
+    pub text: Option<String>,
+}
+
+/// Window decorations requested through `_MOTIF_WM_HINTS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MotifDecorations {
+    pub border: bool,
+    pub resize_handles: bool,
+    pub title: bool,
+    pub menu: bool,
+    pub minimize_button: bool,
+    pub maximize_button: bool,
+}
+
+impl MotifDecorations {
+    pub const ALL: Self = Self {
+        border: true,
+        resize_handles: true,
+        title: true,
+        menu: true,
+        minimize_button: true,
+        maximize_button: true,
+    };
+    pub const NONE: Self = Self {
+        border: false,
+        resize_handles: false,
+        title: false,
+        menu: false,
+        minimize_button: false,
+        maximize_button: false,
+    };
+}
+
+/// Window manager actions allowed through `_MOTIF_WM_HINTS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MotifFunctions {
+    pub resize: bool,
+    pub move_: bool,
+    pub minimize: bool,
+    pub maximize: bool,
+    pub close: bool,
+}
+
+impl MotifFunctions {
+    pub const ALL: Self = Self {
+        resize: true,
+        move_: true,
+        minimize: true,
+        maximize: true,
+        close: true,
+    };
+}
+
+/// `_MOTIF_WM_HINTS`. Fields left as `None` are decided by the window manager.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MotifHints {
+    pub decorations: Option<MotifDecorations>,
+    pub functions: Option<MotifFunctions>,
+}
+
+/// Modifier state decoded from the `state` of an X input event. `ALT`, `NUM_LOCK` and `SUPER`
+/// follow the usual Mod1, Mod2 and Mod4 mapping.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers(u32);
+
+impl Modifiers {
+    pub const SHIFT: Self = Self(ShiftMask);
+    pub const CAPS_LOCK: Self = Self(LockMask);
+    pub const CONTROL: Self = Self(ControlMask);
+    pub const ALT: Self = Self(Mod1Mask);
+    pub const NUM_LOCK: Self = Self(Mod2Mask);
+    pub const MOD3: Self = Self(Mod3Mask);
+    pub const SUPER: Self = Self(Mod4Mask);
+    pub const MOD5: Self = Self(Mod5Mask);
+
+    pub fn from_state(state: u32) -> Self {
+        Self(
+            state
+                & (ShiftMask
+                    | LockMask
+                    | ControlMask
+                    | Mod1Mask
+                    | Mod2Mask
+                    | Mod3Mask
+                    | Mod4Mask
+                    | Mod5Mask),
+        )
+    }
+
+    pub fn empty() -> Self {
+        Self(0)
+    }
+
+    pub fn bits(self) -> u32 {
         self.0
     }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Modifiers {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MotionEvent {
+    pub x: i32,
+    pub y: i32,
+    pub x_root: i32,
+    pub y_root: i32,
+    pub state: u32,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -277,48 +1004,149 @@ impl AsRaw<c_ulong> for Pixel {
     }
 }
 
-pub struct PixMap {
+/// A pixmap. Ones made with [`PixMap::create`] are freed on drop.
+pub struct PixMap<'d> {
     pixmap: c_ulong,
+    display: *mut x11::xlib::Display,
+    owned: bool,
+    _display: PhantomData<&'d Display>,
 }
 
-impl PixMap {
-    pub fn create(display: &Display, window: &Window, width: u32, height: u32, depth: u32) -> Self {
-        let pixmap = unsafe {
-            XCreatePixmap(
-                display.display,
-                window.buffer,
-                width as c_uint,
-                height as c_uint,
-                depth as c_uint,
-            )
-        };
-        Self { pixmap }
-    }
-
-    pub fn from_raw(
-        display: &Display,
-        window: c_ulong,
+impl<'d> PixMap<'d> {
+    pub fn create(
+        display: &'d Display,
+        window: &WindowRef,
         width: u32,
         height: u32,
         depth: u32,
-    ) -> Self {
-        let pixmap = unsafe {
+    ) -> Result<Self, XError> {
+        let pixmap = display.checked(|| unsafe {
             XCreatePixmap(
                 display.display,
-                window,
+                window.window,
                 width as c_uint,
                 height as c_uint,
                 depth as c_uint,
             )
-        };
-        Self { pixmap }
+        })?;
+        Ok(Self {
+            pixmap,
+            display: display.display,
+            owned: true,
+            _display: PhantomData,
+        })
     }
-}
 
-impl AsRaw<c_ulong> for PixMap {
-    fn as_raw(&self) -> c_ulong {
-        self.pixmap
+    /// Wraps a pixmap created elsewhere. It is not freed on drop.
+    pub fn from_raw(display: &'d Display, pixmap: c_ulong) -> Self {
+        Self {
+            pixmap,
+            display: display.display,
+            owned: false,
+            _display: PhantomData,
+        }
     }
+
+    /// Gives up ownership, e.g. to hand the pixmap to another client through a property.
+    pub fn into_raw(mut self) -> c_ulong {
+        self.owned = false;
+        self.pixmap
+    }
+}
+
+impl Drop for PixMap<'_> {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe {
+                XFreePixmap(self.display, self.pixmap);
+            }
+        }
+    }
+}
+
+impl AsRaw<c_ulong> for PixMap<'_> {
+    fn as_raw(&self) -> c_ulong {
+        self.pixmap
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Property {
+    pub type_: Atom,
+    pub data: PropertyData,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PropertyData {
+    Format8(Vec<u8>),
+    Format16(Vec<u16>),
+    /// Xlib passes 32-bit items as C longs; safex converts to and from `u32` at the boundary.
+    Format32(Vec<u32>),
+}
+
+impl PropertyData {
+    pub fn len(&self) -> usize {
+        match self {
+            PropertyData::Format8(items) => items.len(),
+            PropertyData::Format16(items) => items.len(),
+            PropertyData::Format32(items) => items.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Joins chunks of a property that was read in pieces
+    fn append(self, other: PropertyData) -> PropertyData {
+        match (self, other) {
+            (PropertyData::Format8(mut items), PropertyData::Format8(other)) => {
+                items.extend(other);
+                PropertyData::Format8(items)
+            }
+            (PropertyData::Format16(mut items), PropertyData::Format16(other)) => {
+                items.extend(other);
+                PropertyData::Format16(items)
+            }
+            (PropertyData::Format32(mut items), PropertyData::Format32(other)) => {
+                items.extend(other);
+                PropertyData::Format32(items)
+            }
+            // The format changed under us; the newer data wins
+            (_, other) => other,
+        }
+    }
+}
+
+/// Who draws the text an input method is composing, before it is committed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PreeditStyle {
+    /// The application does, from `WindowEvent::Preedit*` events.
+    Callbacks,
+    /// The input method does, at the spot set with [`WindowRef::set_ime_spot`].
+    OverTheSpot,
+    /// The input method does, in a window of its own.
+    Root,
+    /// Nobody; only committed text is delivered.
+    NoPreedit,
+}
+
+impl PreeditStyle {
+    fn as_raw(self) -> c_ulong {
+        (match self {
+            PreeditStyle::Callbacks => XIMPreeditCallbacks | XIMStatusNothing,
+            PreeditStyle::OverTheSpot => XIMPreeditPosition | XIMStatusNothing,
+            PreeditStyle::Root => XIMPreeditNothing | XIMStatusNothing,
+            PreeditStyle::NoPreedit => XIMPreeditNone | XIMStatusNone,
+        }) as c_ulong
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PropertyMode {
+    Replace,
+    Prepend,
+    Append,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -361,6 +1189,84 @@ impl AsRaw<*mut x11::xlib::Screen> for Screen {
     }
 }
 
+/// How [`Window::restack_relative`] places a window relative to its sibling.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackMode {
+    Above,
+    Below,
+    TopIf,
+    BottomIf,
+    Opposite,
+}
+
+/// ICCCM `WM_NORMAL_HINTS`. Fields left as `None` are not sent to the window manager.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeHints {
+    pub position: Option<(i32, i32)>,
+    /// Whether `position` was chosen by the user rather than the program.
+    pub user_position: bool,
+    pub size: Option<(u32, u32)>,
+    /// Whether `size` was chosen by the user rather than the program.
+    pub user_size: bool,
+    pub min_size: Option<(u32, u32)>,
+    pub max_size: Option<(u32, u32)>,
+    pub resize_increments: Option<(u32, u32)>,
+    /// Minimum and maximum aspect ratio, each as `(numerator, denominator)`.
+    pub aspect: Option<((i32, i32), (i32, i32))>,
+    pub base_size: Option<(u32, u32)>,
+    pub gravity: Option<Gravity>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SelectionContent {
+    // (target, type, data)
+    targets: Vec<(Atom, Atom, Vec<u8>)>,
+}
+
+impl SelectionContent {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Offers `text` under every target text-aware clients ask for.
+    pub fn text(display: &Display, text: &str) -> Self {
+        let utf8_string = display.intern_atom("UTF8_STRING", false).unwrap();
+        let latin1 = text
+            .chars()
+            .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+            .collect();
+
+        let mut content = Self::new()
+            .with(utf8_string, text.as_bytes().to_vec())
+            .with(Atom::STRING, latin1);
+        for target in ["TEXT", "text/plain;charset=utf-8", "text/plain"] {
+            let target = display.intern_atom(target, false).unwrap();
+            content
+                .targets
+                .push((target, utf8_string, text.as_bytes().to_vec()));
+        }
+        content
+    }
+
+    /// Offers `paths` as a `text/uri-list`, which is what file managers expect from a drag.
+    pub fn files(display: &Display, paths: &[&Path]) -> Self {
+        let uri_list = display.intern_atom("text/uri-list", false).unwrap();
+        let list: String = paths
+            .iter()
+            .map(|path| format!("file://{}\r\n", _percent_encode(path)))
+            .collect();
+
+        Self::new().with(uri_list, list.into_bytes())
+    }
+
+    /// Offers `data` under `target`, e.g. an interned MIME type such as `image/png`.
+    pub fn with(mut self, target: Atom, data: Vec<u8>) -> Self {
+        self.targets.retain(|(existing, _, _)| *existing != target);
+        self.targets.push((target, target, data));
+        self
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Visual {
     visual: *mut x11::xlib::Visual,
@@ -402,9 +1308,15 @@ pub struct WindowAttributesBuilder {
     attributes: XSetWindowAttributes,
 }
 
+impl Default for WindowAttributesBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WindowAttributesBuilder {
     pub fn new() -> Self {
-        let attributes: XSetWindowAttributes = unsafe { MaybeUninit::uninit().assume_init() };
+        let attributes: XSetWindowAttributes = unsafe { std::mem::zeroed() };
 
         Self { attributes }
     }
@@ -424,7 +1336,7 @@ impl WindowAttributesBuilder {
         self
     }
 
-    pub fn colormap(mut self, cmap: ColorMap) -> Self {
+    pub fn colormap(mut self, cmap: &ColorMap) -> Self {
         self.attributes.colormap = cmap.as_raw();
         self
     }
@@ -441,35 +1353,228 @@ impl AsRaw<XSetWindowAttributes> for WindowAttributesBuilder {
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visibility {
+    Unobscured,
+    PartiallyObscured,
+    FullyObscured,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WindowId(c_ulong);
+
+impl AsRaw<c_ulong> for WindowId {
+    fn as_raw(&self) -> c_ulong {
+        self.0
+    }
+}
+
+/// The EWMH `_NET_WM_STATE` flags safex knows about.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WindowState {
+    pub fullscreen: bool,
+    pub maximized_horizontal: bool,
+    pub maximized_vertical: bool,
+    pub minimized: bool,
+    pub above: bool,
+    pub sticky: bool,
+    pub skip_taskbar: bool,
+    pub modal: bool,
+}
+
+/// The EWMH `_NET_WM_WINDOW_TYPE` values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WindowType {
+    Normal,
+    Dialog,
+    Utility,
+    Splash,
+    Dock,
+    Desktop,
+    Toolbar,
+    Menu,
+    DropdownMenu,
+    PopupMenu,
+    Tooltip,
+    Notification,
+    Combo,
+    Dnd,
+}
+
+impl WindowType {
+    const ALL: [Self; 14] = [
+        Self::Normal,
+        Self::Dialog,
+        Self::Utility,
+        Self::Splash,
+        Self::Dock,
+        Self::Desktop,
+        Self::Toolbar,
+        Self::Menu,
+        Self::DropdownMenu,
+        Self::PopupMenu,
+        Self::Tooltip,
+        Self::Notification,
+        Self::Combo,
+        Self::Dnd,
+    ];
+
+    fn atom_name(self) -> &'static str {
+        match self {
+            Self::Normal => "_NET_WM_WINDOW_TYPE_NORMAL",
+            Self::Dialog => "_NET_WM_WINDOW_TYPE_DIALOG",
+            Self::Utility => "_NET_WM_WINDOW_TYPE_UTILITY",
+            Self::Splash => "_NET_WM_WINDOW_TYPE_SPLASH",
+            Self::Dock => "_NET_WM_WINDOW_TYPE_DOCK",
+            Self::Desktop => "_NET_WM_WINDOW_TYPE_DESKTOP",
+            Self::Toolbar => "_NET_WM_WINDOW_TYPE_TOOLBAR",
+            Self::Menu => "_NET_WM_WINDOW_TYPE_MENU",
+            Self::DropdownMenu => "_NET_WM_WINDOW_TYPE_DROPDOWN_MENU",
+            Self::PopupMenu => "_NET_WM_WINDOW_TYPE_POPUP_MENU",
+            Self::Tooltip => "_NET_WM_WINDOW_TYPE_TOOLTIP",
+            Self::Notification => "_NET_WM_WINDOW_TYPE_NOTIFICATION",
+            Self::Combo => "_NET_WM_WINDOW_TYPE_COMBO",
+            Self::Dnd => "_NET_WM_WINDOW_TYPE_DND",
+        }
+    }
+}
+
+/// ICCCM `WM_HINTS`. Fields left as `None` are not sent to the window manager.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WmHints {
+    /// Whether the window relies on the window manager to give it keyboard focus.
+    pub input: Option<bool>,
+    pub initial_state: Option<InitialState>,
+    pub icon_pixmap: Option<c_ulong>,
+    pub icon_mask: Option<c_ulong>,
+    pub window_group: Option<WindowId>,
+    pub urgent: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum WindowEvent {
     Expose,
+    /// Delivered once per loop iteration after a `Window::request_redraw` call.
+    RedrawRequested,
+    /// The user asked the window manager to close the window.
+    CloseRequested,
+    /// Delivered when the event queue has been drained, right before the loop blocks.
+    /// It is not tied to a window, so it comes without a `WindowId`.
+    AboutToWait,
+    KeyPress(KeyEvent),
+    KeyRelease(KeyEvent),
+    ButtonPress(ButtonEvent),
+    ButtonRelease(ButtonEvent),
+    MotionNotify(MotionEvent),
+    EnterNotify(CrossingEvent),
+    LeaveNotify(CrossingEvent),
+    FocusIn,
+    FocusOut,
+    ConfigureNotify {
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+        border_width: u32,
+    },
+    MapNotify,
+    UnmapNotify,
+    DestroyNotify,
+    ReparentNotify {
+        parent: c_ulong,
+        x: i32,
+        y: i32,
+    },
+    PropertyNotify {
+        atom: Atom,
+        deleted: bool,
+    },
+    ClientMessage(ClientMessageEvent),
+    VisibilityNotify(Visibility),
+    /// Another client took over a selection this window owned.
+    SelectionLost(Atom),
+    /// Files are being dragged over the window.
+    FileHovered(Vec<PathBuf>),
+    /// The drag left the window or was cancelled before anything was dropped.
+    FileHoverCancelled,
+    FileDropped(Vec<PathBuf>),
+    /// Answer to a `XConvertSelection` request that was not made through `convert_selection`.
+    SelectionNotify {
+        selection: Atom,
+        target: Atom,
+        property: Option<Atom>,
+    },
+    /// The input method started composing text (with [`PreeditStyle::Callbacks`]).
+    PreeditStart,
+    /// The text being composed changed; `caret` is a character index into `text`.
+    Preedit {
+        text: String,
+        caret: usize,
+    },
+    /// Composition ended, either committed as a key press with text or cancelled.
+    PreeditEnd,
+    /// The active keyboard layout changed, see [`crate::xkb::select_events`]. Like
+    /// `AboutToWait`, it comes without a `WindowId`.
+    #[cfg(feature = "xkb")]
+    KeyboardGroupChanged(u32),
+    /// The keymap was edited or replaced; keysyms looked up earlier may be stale.
+    #[cfg(feature = "xkb")]
+    KeymapChanged,
+    /// Input devices were plugged in, removed, enabled, disabled or reattached, see
+    /// [`crate::xinput::select_hierarchy_events`]. Comes without a `WindowId`.
+    #[cfg(feature = "xinput")]
+    DeviceHierarchyChanged(Vec<crate::xinput::HierarchyChange>),
+    /// A device's capabilities changed. `slave_switch` is set when a master device only
+    /// changed because a different physical device (`source`) started driving it.
+    #[cfg(feature = "xinput")]
+    DeviceChanged {
+        device: i32,
+        source: i32,
+        slave_switch: bool,
+        classes: Vec<crate::xinput::DeviceClass>,
+    },
+}
+
+/// A window created by safex. Dropping it destroys the window.
+///
+/// All drawing and property methods live on [`WindowRef`], which `Window` derefs to.
+pub struct Window<'d> {
+    window: WindowRef<'d>,
+    // The colormap `new_with_glx` made for the window, freed after the window is destroyed
+    _colormap: Option<ColorMap<'d>>,
 }
 
-pub struct Window {
+/// A window safex did not create, adopted with [`Window::from_raw`]. Dropping it releases
+/// the state safex attached to it, such as a back buffer, but leaves the window alone.
+pub struct ForeignWindow<'d> {
+    window: WindowRef<'d>,
+}
+
+/// A view of any window, such as the root window. Dropping it does nothing.
+pub struct WindowRef<'d> {
     window: Buffer,
-    buffer: Buffer,
     display: *mut x11::xlib::Display,
-    gc: GC,
+    gc: GC<'d>,
+    // The pixmap and mask `set_icon_pixmap` put in WM_HINTS
+    icon: RefCell<Option<(PixMap<'d>, PixMap<'d>)>>,
+    // Shared with BACK_BUFFERS, where the event loop finds it to follow resizes
+    back_buffer: Option<SharedBackBuffer>,
 }
 
-impl Window {
-    pub fn root_window(display: &Display, screen: &Screen) -> Self {
-        let window = unsafe { XRootWindow(display.display, XScreenNumberOfScreen(screen.screen)) };
-        let gc = GC(unsafe { XDefaultGC(display.display, XScreenNumberOfScreen(screen.screen)) });
-        unsafe { XSelectInput(display.display, window, ExposureMask as c_long) };
-        Self {
-            window,
-            buffer: window,
-            display: display.display,
-            gc,
+impl<'d> Window<'d> {
+    pub fn root_window(display: &'d Display, screen: &Screen) -> WindowRef<'d> {
+        unsafe {
+            let window = XRootWindow(display.display, XScreenNumberOfScreen(screen.screen));
+            WindowRef::new(display.display, window)
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create(
-        display: &Display,
+        display: &'d Display,
         screen: &Screen,
-        buffer: Option<()>,
-        parent: Option<Window>,
+        buffer: BufferMode,
+        parent: Option<&WindowRef>,
         x: i32,
         y: i32,
         width: u32,
@@ -480,19 +1585,15 @@ impl Window {
         visual: &Visual,
         valuemask: WindowAttribute,
         mut attributes: WindowAttributesBuilder,
-    ) -> Self {
-        unsafe {
-            let parent = match parent {
-                None => 0,
-                Some(p) => p.buffer,
-            };
-
-            let gc = GC(XDefaultGC(
-                display.display,
-                XScreenNumberOfScreen(screen.screen),
-            ));
+    ) -> Result<Self, XError> {
+        // Without a parent the window is a top-level on `screen`
+        let parent = match parent {
+            None => unsafe { XRootWindowOfScreen(screen.screen) },
+            Some(p) => p.window,
+        };
 
-            let window = XCreateWindow(
+        let window = display.checked(|| unsafe {
+            XCreateWindow(
                 display.display,
                 parent,
                 x as c_int,
@@ -505,29 +1606,42 @@ impl Window {
                 visual.visual,
                 valuemask as c_ulong,
                 &mut attributes.attributes,
-            );
-
-            let buffer = match buffer {
-                None => window,
-                Some(_) => PixMap::from_raw(&display, window, width, height, depth as u32).pixmap,
-            };
-
-            unsafe { XSelectInput(display.display, window, ExposureMask as c_long) };
+            )
+        })?;
 
-            Self {
-                window,
-                buffer,
-                display: display.display,
-                gc,
+        let mut window = unsafe {
+            if valuemask & CWEventMask == 0 {
+                XSelectInput(display.display, window, DEFAULT_EVENT_MASK as c_long);
+            }
+            _set_wm_protocols(display.display, window);
+            if _is_root_window(display.display, parent) {
+                _set_client_properties(display.display, window);
             }
+            WindowRef::new(display.display, window)
+        };
+        if buffer != BufferMode::Direct {
+            let background = if valuemask & CWBackPixel != 0 {
+                attributes.attributes.background_pixel
+            } else {
+                0
+            };
+            window.back_buffer = Some(unsafe {
+                _create_back_buffer(display.display, window.window, buffer, background)
+            });
         }
+
+        Ok(Self {
+            window,
+            _colormap: None,
+        })
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn create_simple(
-        display: &Display,
+        display: &'d Display,
         screen: &Screen,
-        buffer: Option<()>,
-        parent: Option<Window>,
+        buffer: BufferMode,
+        parent: Option<&WindowRef>,
         x: i32,
         y: i32,
         width: u32,
@@ -535,19 +1649,15 @@ impl Window {
         border_width: u32,
         border: u64,
         pixel: Pixel,
-    ) -> Self {
-        unsafe {
-            let parent = match parent {
-                None => 0,
-                Some(p) => p.buffer,
-            };
-
-            let gc = GC(XDefaultGC(
-                display.display,
-                XScreenNumberOfScreen(screen.screen),
-            ));
+    ) -> Result<Self, XError> {
+        // Without a parent the window is a top-level on `screen`
+        let parent = match parent {
+            None => unsafe { XRootWindowOfScreen(screen.screen) },
+            Some(p) => p.window,
+        };
 
-            let window = XCreateSimpleWindow(
+        let window = display.checked(|| unsafe {
+            XCreateSimpleWindow(
                 display.display,
                 parent,
                 x as c_int,
@@ -557,51 +1667,53 @@ impl Window {
                 border_width as c_uint,
                 border as c_ulong,
                 pixel.pixel,
-            );
-
-            let geometry = _get_geometry(display.display, window);
-
-            let buffer = match buffer {
-                None => window,
-                Some(_) => PixMap::from_raw(&display, window, width, height, geometry.depth).pixmap,
-            };
-
-            unsafe { XSelectInput(display.display, window, ExposureMask as c_long) };
+            )
+        })?;
 
-            Self {
-                window,
-                buffer,
-                display: display.display,
-                gc,
+        let mut window = unsafe {
+            XSelectInput(display.display, window, DEFAULT_EVENT_MASK as c_long);
+            _set_wm_protocols(display.display, window);
+            if _is_root_window(display.display, parent) {
+                _set_client_properties(display.display, window);
             }
+            WindowRef::new(display.display, window)
+        };
+        if buffer != BufferMode::Direct {
+            window.back_buffer = Some(unsafe {
+                _create_back_buffer(display.display, window.window, buffer, pixel.pixel)
+            });
         }
+
+        Ok(Self {
+            window,
+            _colormap: None,
+        })
     }
 
     #[cfg(feature = "glx")]
+    #[allow(clippy::too_many_arguments)]
     pub fn new_with_glx(
-        display: &Display,
+        display: &'d Display,
         screen: &Screen,
         vi: &VisualInfo,
-        buffer: Option<()>,
+        buffer: BufferMode,
         x: i32,
         y: i32,
         width: u32,
         height: u32,
         border_width: u32,
-        depth: i32,
+        _depth: i32,
         _class: WindowClass,
         _visual: &VisualInfo,
-    ) -> Result<Self, ()> {
+    ) -> Result<Self, XError> {
         let root = Window::root_window(display, screen);
-        let cmap = ColorMap::create(display, &root, &vi.visual);
+        let cmap = ColorMap::create(display, &root, &vi.visual)?;
 
         let attribute = WindowAttributesBuilder::new()
-            .colormap(cmap)
-            .event_mask(ExposureMask | KeyPressMask);
+            .colormap(&cmap)
+            .event_mask(DEFAULT_EVENT_MASK);
 
-        let gc = GC(unsafe { XDefaultGC(display.display, XScreenNumberOfScreen(screen.screen)) });
-
-        let window = unsafe {
+        let window = display.checked(|| unsafe {
             XCreateWindow(
                 display.display,
                 root.as_raw(),
@@ -616,23 +1728,62 @@ impl Window {
                 CWColormap | CWEventMask,
                 &mut attribute.as_raw(),
             )
-        };
+        })?;
 
-        let buffer = match buffer {
-            None => window,
-            Some(_) => PixMap::from_raw(&display, window, width, height, depth as u32).pixmap,
+        let mut window = unsafe {
+            _set_wm_protocols(display.display, window);
+            _set_client_properties(display.display, window);
+            WindowRef::new(display.display, window)
         };
-
-        unsafe { XSelectInput(display.display, window, ExposureMask as c_long) };
+        if buffer != BufferMode::Direct {
+            window.back_buffer =
+                Some(unsafe { _create_back_buffer(display.display, window.window, buffer, 0) });
+        }
 
         Ok(Self {
             window,
-            display: display.as_raw(),
-            buffer,
-            gc,
+            _colormap: Some(cmap),
         })
     }
 
+    /// Adopts a window created elsewhere, e.g. by winit. A back buffer adds
+    /// `StructureNotifyMask` to what this client selected on the window, to follow its size.
+    ///
+    /// # Safety
+    ///
+    /// `window` must be a window on `display` that outlives the returned value.
+    pub unsafe fn from_raw(
+        display: &'d Display,
+        _screen: &Screen,
+        window: c_ulong,
+        buffer: BufferMode,
+    ) -> ForeignWindow<'d> {
+        let mut window = WindowRef::new(display.display, window);
+        if buffer != BufferMode::Direct {
+            window.back_buffer = Some(_create_back_buffer(
+                display.display,
+                window.window,
+                buffer,
+                0,
+            ));
+        }
+
+        ForeignWindow { window }
+    }
+}
+
+impl<'d> WindowRef<'d> {
+    // Drawing goes through the default GC of the window's screen
+    unsafe fn new(display: *mut x11::xlib::Display, window: c_ulong) -> Self {
+        Self {
+            window,
+            display,
+            gc: GC::borrowed(display, _get_screen_number(display, window)),
+            icon: RefCell::new(None),
+            back_buffer: None,
+        }
+    }
+
     #[cfg(feature = "glx")]
     pub fn glx_swap_buffers(&self) {
         unsafe {
@@ -640,21 +1791,60 @@ impl Window {
         }
     }
 
+    /// Sets the title as UTF-8 through `_NET_WM_NAME`, and through `WM_NAME` as `STRING` or
+    /// compound text for window managers that predate EWMH. The icon name follows the title.
     pub fn set_window_title(&self, title: &str) {
+        // Strip NULs once, so WM_NAME and _NET_WM_NAME carry the same text
+        let title = title.replace('\0', "");
+        let c_title = CString::new(title.as_str()).unwrap();
         unsafe {
-            let title_str = CString::new(title).unwrap();
-            XStoreName(self.display, self.window, title_str.as_ptr() as *mut c_char);
+            Xutf8SetWMProperties(
+                self.display,
+                self.window,
+                c_title.as_ptr(),
+                c_title.as_ptr(),
+                null_mut(),
+                0,
+                null_mut(),
+                null_mut(),
+                null_mut(),
+            );
         }
+
+        self.set_utf8_property(_intern(self.display, "_NET_WM_NAME"), &title);
+        self.set_utf8_property(_intern(self.display, "_NET_WM_ICON_NAME"), &title);
     }
 
-    pub fn get_window_title(&self) -> String {
+    pub fn get_window_title(&self) -> Option<String> {
+        if let Ok(Some(title)) = self.get_utf8_property(_intern(self.display, "_NET_WM_NAME")) {
+            return Some(title);
+        }
+
         unsafe {
-            let mut name = std::mem::zeroed();
-            XFetchName(self.display, self.as_raw(), &mut name);
-            CStr::from_ptr(name as *const c_char)
-                .to_str()
-                .unwrap()
-                .to_string()
+            let mut property: XTextProperty = std::mem::zeroed();
+            if XGetWMName(self.display, self.window, &mut property) == 0 || property.value.is_null()
+            {
+                return None;
+            }
+
+            let mut list = null_mut();
+            let mut count = 0;
+            let status =
+                Xutf8TextPropertyToTextList(self.display, &property, &mut list, &mut count);
+            XFree(property.value as *mut _);
+
+            // Success is 0; positive values count unconvertible characters, which are replaced
+            if status < 0 || list.is_null() {
+                return None;
+            }
+
+            let title = std::slice::from_raw_parts(list, count as usize)
+                .iter()
+                .map(|string| CStr::from_ptr(*string).to_string_lossy())
+                .collect::<String>();
+            XFreeStringList(list);
+
+            Some(title)
         }
     }
 
@@ -671,18 +1861,16 @@ impl Window {
     }
 
     pub fn set_window_background(&self, pixel: Pixel) {
-        unsafe {
-            let geometry = self.get_geometry();
-            let rect = Rectangle {
-                x: 0,
-                y: 0,
-                width: geometry.width,
-                height: geometry.height,
-                pixel,
-            };
+        let geometry = self.get_geometry();
+        let rect = Rectangle {
+            x: 0,
+            y: 0,
+            width: geometry.width,
+            height: geometry.height,
+            pixel,
+        };
 
-            self.fill_rectangle(rect);
-        }
+        self.fill_rectangle(rect);
     }
 
     pub fn fill_rectangle(&self, rect: Rectangle) {
@@ -691,7 +1879,7 @@ impl Window {
             XSetBackground(self.display, self.gc.as_raw(), rect.pixel.pixel);
             XFillRectangle(
                 self.display,
-                self.buffer,
+                self.drawable(),
                 self.gc.as_raw(),
                 rect.x as c_int,
                 rect.y as c_int,
@@ -707,7 +1895,7 @@ impl Window {
             XSetBackground(self.display, self.gc.as_raw(), rect.pixel.pixel);
             XDrawRectangle(
                 self.display,
-                self.buffer,
+                self.drawable(),
                 self.gc.as_raw(),
                 rect.x as c_int,
                 rect.y as c_int,
@@ -723,7 +1911,7 @@ impl Window {
             XSetBackground(self.display, self.gc.as_raw(), arc.pixel.pixel);
             XFillArc(
                 self.display,
-                self.buffer,
+                self.drawable(),
                 self.gc.as_raw(),
                 arc.x as c_int,
                 arc.y as c_int,
@@ -741,7 +1929,7 @@ impl Window {
             XSetBackground(self.display, self.gc.as_raw(), arc.pixel.pixel);
             XDrawArc(
                 self.display,
-                self.buffer,
+                self.drawable(),
                 self.gc.as_raw(),
                 arc.x as c_int,
                 arc.y as c_int,
@@ -760,7 +1948,7 @@ impl Window {
             let len = string.len();
             XDrawString(
                 self.display,
-                self.buffer,
+                self.drawable(),
                 self.gc.as_raw(),
                 x as c_int,
                 y as c_int,
@@ -771,32 +1959,181 @@ impl Window {
     }
 
     pub fn get_geometry(&self) -> Geometry {
-        unsafe { _get_geometry(self.display, self.window) }
+        _get_geometry(self.display, self.window)
+    }
+
+    /// Moves the window; the WM may adjust or ignore the request for top-level windows.
+    pub fn move_to(&self, x: i32, y: i32) {
+        let mut changes = unsafe { std::mem::zeroed::<XWindowChanges>() };
+        changes.x = x;
+        changes.y = y;
+        self.configure(CWX | CWY, &mut changes);
+    }
+
+    pub fn resize(&self, width: u32, height: u32) {
+        let mut changes = unsafe { std::mem::zeroed::<XWindowChanges>() };
+        changes.width = width as c_int;
+        changes.height = height as c_int;
+        self.configure(CWWidth | CWHeight, &mut changes);
+    }
+
+    pub fn move_resize(&self, x: i32, y: i32, width: u32, height: u32) {
+        let mut changes = unsafe { std::mem::zeroed::<XWindowChanges>() };
+        changes.x = x;
+        changes.y = y;
+        changes.width = width as c_int;
+        changes.height = height as c_int;
+        self.configure(CWX | CWY | CWWidth | CWHeight, &mut changes);
+    }
+
+    pub fn set_border_width(&self, border_width: u32) {
+        let mut changes = unsafe { std::mem::zeroed::<XWindowChanges>() };
+        changes.border_width = border_width as c_int;
+        self.configure(CWBorderWidth, &mut changes);
+    }
+
+    pub fn raise(&self) {
+        let mut changes = unsafe { std::mem::zeroed::<XWindowChanges>() };
+        changes.stack_mode = Above;
+        self.configure(CWStackMode, &mut changes);
+    }
+
+    pub fn lower(&self) {
+        let mut changes = unsafe { std::mem::zeroed::<XWindowChanges>() };
+        changes.stack_mode = Below;
+        self.configure(CWStackMode, &mut changes);
+    }
+
+    /// Restacks the window relative to `sibling`, which must share its parent.
+    pub fn restack_relative(&self, sibling: &WindowRef, mode: StackMode) {
+        let mut changes = unsafe { std::mem::zeroed::<XWindowChanges>() };
+        changes.sibling = sibling.window;
+        changes.stack_mode = match mode {
+            StackMode::Above => Above,
+            StackMode::Below => Below,
+            StackMode::TopIf => TopIf,
+            StackMode::BottomIf => BottomIf,
+            StackMode::Opposite => Opposite,
+        };
+        self.configure(CWSibling | CWStackMode, &mut changes);
+    }
+
+    // XReconfigureWMWindow is XConfigureWindow, except that it asks the WM instead when a
+    // reparenting WM has made the sibling no longer a real sibling
+    fn configure(&self, mask: c_ushort, changes: &mut XWindowChanges) {
+        unsafe {
+            let screen = _get_screen_number(self.display, self.window);
+            XReconfigureWMWindow(self.display, self.window, screen, mask as c_uint, changes);
+        }
+    }
+
+    /// Translates a point in this window to root window coordinates. Unlike [`get_geometry`],
+    /// this accounts for any frame the WM has reparented the window into.
+    ///
+    /// [`get_geometry`]: WindowRef::get_geometry
+    pub fn translate_coordinates(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        unsafe {
+            let root = XRootWindow(self.display, _get_screen_number(self.display, self.window));
+            let (mut root_x, mut root_y, mut child) = (0, 0, 0);
+            let same_screen = XTranslateCoordinates(
+                self.display,
+                self.window,
+                root,
+                x,
+                y,
+                &mut root_x,
+                &mut root_y,
+                &mut child,
+            );
+            (same_screen != 0).then_some((root_x, root_y))
+        }
+    }
+
+    /// Reads `_NET_FRAME_EXTENTS`. Returns `None` if the window is undecorated or the WM does
+    /// not set it.
+    pub fn get_frame_extents(&self) -> Result<Option<FrameExtents>, XError> {
+        Ok(self
+            .get_cardinal_property(_intern(self.display, "_NET_FRAME_EXTENTS"))?
+            .filter(|extents| extents.len() >= 4)
+            .map(|extents| FrameExtents {
+                left: extents[0],
+                right: extents[1],
+                top: extents[2],
+                bottom: extents[3],
+            }))
     }
 
-    pub fn get_gc(&self) -> &GC {
+    pub fn get_gc(&self) -> &GC<'d> {
         &self.gc
     }
 
+    /// Presents the back buffer: swaps it to the front with DBE, otherwise copies the pixmap
+    /// onto the window. Does nothing for [`BufferMode::Direct`].
     pub fn copy_to_buffer(&self) {
-        unsafe {
-            let geometry = self.get_geometry();
+        let Some(back_buffer) = &self.back_buffer else {
+            return;
+        };
+        let back_buffer = back_buffer.lock().unwrap();
+
+        #[cfg(feature = "dbe")]
+        if let Some(swap_action) = back_buffer.swap_action {
+            unsafe { dbe::_swap_buffers(self.display, self.window, swap_action) };
+            return;
+        }
 
+        unsafe {
             XCopyArea(
                 self.display,
-                self.buffer,
+                back_buffer.drawable,
                 self.window,
                 self.gc.as_raw(),
                 0,
                 0,
-                geometry.width as c_uint,
-                geometry.height as c_uint,
+                back_buffer.width as c_uint,
+                back_buffer.height as c_uint,
                 0,
                 0,
             );
         }
     }
 
+    /// Chooses whether a pixmap back buffer keeps its content when the window is resized, or
+    /// is cleared to the window background. Content is kept by default.
+    pub fn set_preserve_back_buffer(&self, preserve: bool) {
+        if let Some(back_buffer) = &self.back_buffer {
+            back_buffer.lock().unwrap().preserve = preserve;
+        }
+    }
+
+    /// Sets what a DBE back buffer holds after [`Window::copy_to_buffer`]. Defaults to
+    /// [`SwapAction::Copied`]; has no effect on pixmap back buffers.
+    #[cfg(feature = "dbe")]
+    pub fn set_swap_action(&self, swap_action: SwapAction) {
+        if let Some(back_buffer) = &self.back_buffer {
+            let mut back_buffer = back_buffer.lock().unwrap();
+            if back_buffer.swap_action.is_some() {
+                back_buffer.swap_action = Some(swap_action);
+            }
+        }
+    }
+
+    /// Returns whether drawing goes to a DBE back buffer rather than a pixmap or the window.
+    #[cfg(feature = "dbe")]
+    pub fn is_dbe_buffered(&self) -> bool {
+        self.back_buffer
+            .as_ref()
+            .is_some_and(|back_buffer| back_buffer.lock().unwrap().is_dbe())
+    }
+
+    // The back buffer if there is one, otherwise the window itself
+    fn drawable(&self) -> c_ulong {
+        self.back_buffer
+            .as_ref()
+            .map_or(self.window, |back_buffer| {
+                back_buffer.lock().unwrap().drawable
+            })
+    }
+
     pub fn flush_gc(&self) {
         unsafe {
             XFlushGC(self.display, self.gc.as_raw());
@@ -815,77 +2152,3503 @@ impl Window {
         }
     }
 
-    pub fn run<F>(&self, mut func: F)
-    where
-        F: FnMut(WindowEvent, &mut ControlFlow),
-    {
+    /// Windows with a back buffer keep `StructureNotifyMask` whatever `mask` says, since the
+    /// buffer follows the window size through `ConfigureNotify`.
+    pub fn select_input(&self, mask: Mask) {
+        let mask = match self.back_buffer {
+            Some(_) => mask | StructureNotifyMask,
+            None => mask,
+        };
         unsafe {
-            let mut control_flow = ControlFlow::Wait;
-            loop {
-                let event = unsafe {
-                    let mut event = MaybeUninit::uninit();
-                    XNextEvent(self.display, event.as_mut_ptr());
-                    event.assume_init()
-                };
-
-                match event.type_ {
-                    Expose => {
-                        func(WindowEvent::Expose, &mut control_flow);
-                    }
-                    _ => {}
-                }
-            }
+            XSelectInput(self.display, self.window, mask as c_long);
         }
     }
 
-    pub unsafe fn from_raw(
-        display: &Display,
-        screen: &Screen,
-        window: c_ulong,
-        buffer: Option<()>,
-    ) -> Self {
-        let gc = GC(XDefaultGC(
-            display.display,
-            XScreenNumberOfScreen(screen.screen),
-        ));
-        let geometry = _get_geometry(display.display, window);
-        let buffer = match buffer {
-            None => window,
-            Some(_) => {
-                PixMap::from_raw(
-                    &display,
-                    window,
-                    geometry.width,
-                    geometry.height,
-                    geometry.depth,
-                )
-                .pixmap
+    pub fn set_property(
+        &self,
+        property: Atom,
+        type_: Atom,
+        data: &PropertyData,
+        mode: PropertyMode,
+    ) {
+        let longs: Vec<c_long>;
+        let (format, ptr, len) = match data {
+            PropertyData::Format8(data) => (8, data.as_ptr(), data.len()),
+            PropertyData::Format16(data) => (16, data.as_ptr() as *const u8, data.len()),
+            PropertyData::Format32(data) => {
+                longs = data.iter().map(|&item| item as c_long).collect();
+                (32, longs.as_ptr() as *const u8, longs.len())
             }
         };
 
-        Self {
-            window,
-            buffer,
-            display: display.display,
-            gc,
-        }
-    }
-}
+        let mode = match mode {
+            PropertyMode::Replace => PropModeReplace,
+            PropertyMode::Prepend => PropModePrepend,
+            PropertyMode::Append => PropModeAppend,
+        };
 
-impl Drop for Window {
-    fn drop(&mut self) {
         unsafe {
-            if self.buffer != 0 {
-                XDestroyWindow(self.display, self.window);
-            }
+            XChangeProperty(
+                self.display,
+                self.window,
+                property.0,
+                type_.0,
+                format,
+                mode,
+                ptr,
+                len as c_int,
+            );
         }
     }
-}
 
-impl AsRaw<c_ulong> for Window {
-    fn as_raw(&self) -> c_ulong {
-        self.window
-    }
+    /// Reads the whole property, fetching it in chunks if it is large.
+    ///
+    /// Returns `None` if the property does not exist or is not of type `type_`.
+    pub fn get_property(
+        &self,
+        property: Atom,
+        type_: Option<Atom>,
+    ) -> Result<Option<Property>, XError> {
+        const CHUNK_LENGTH: c_long = 16 * 1024;
+
+        let requested_type = type_.unwrap_or(Atom(AnyPropertyType as c_ulong));
+        let mut actual_type = Atom::NONE;
+        let mut data: Option<PropertyData> = None;
+        let mut offset = 0;
+
+        loop {
+            let mut chunk_type = 0;
+            let mut format = 0;
+            let mut nitems = 0;
+            let mut bytes_after = 0;
+            let mut chunk = null_mut();
+
+            _checked(self.display, || unsafe {
+                XGetWindowProperty(
+                    self.display,
+                    self.window,
+                    property.0,
+                    offset,
+                    CHUNK_LENGTH,
+                    False,
+                    requested_type.0,
+                    &mut chunk_type,
+                    &mut format,
+                    &mut nitems,
+                    &mut bytes_after,
+                    &mut chunk,
+                )
+            })?;
+
+            let matches = chunk_type != 0
+                && (type_.is_none() || chunk_type == requested_type.0)
+                && (actual_type == Atom::NONE || chunk_type == actual_type.0);
+            if !matches {
+                if !chunk.is_null() {
+                    unsafe { XFree(chunk as *mut _) };
+                }
+                return Ok(None);
+            }
+            actual_type = Atom(chunk_type);
+
+            let nitems = if chunk.is_null() { 0 } else { nitems as usize };
+            let items = unsafe {
+                let items = match format {
+                    8 => PropertyData::Format8(std::slice::from_raw_parts(chunk, nitems).to_vec()),
+                    16 => PropertyData::Format16(
+                        std::slice::from_raw_parts(chunk as *const u16, nitems).to_vec(),
+                    ),
+                    _ => PropertyData::Format32(
+                        std::slice::from_raw_parts(chunk as *const c_long, nitems)
+                            .iter()
+                            .map(|&item| item as u32)
+                            .collect(),
+                    ),
+                };
+                if !chunk.is_null() {
+                    XFree(chunk as *mut _);
+                }
+                items
+            };
+
+            data = Some(match data {
+                Some(data) => data.append(items),
+                None => items,
+            });
+
+            if bytes_after == 0 {
+                break;
+            }
+            // Offsets are always counted in 32-bit units, whatever the format
+            offset += (nitems * format as usize / 32) as c_long;
+        }
+
+        Ok(data.map(|data| Property {
+            type_: actual_type,
+            data,
+        }))
+    }
+
+    pub fn delete_property(&self, property: Atom) {
+        unsafe {
+            XDeleteProperty(self.display, self.window, property.0);
+        }
+    }
+
+    pub fn list_properties(&self) -> Vec<Atom> {
+        unsafe {
+            let mut len = 0;
+            let atoms = XListProperties(self.display, self.window, &mut len);
+            if atoms.is_null() {
+                return Vec::new();
+            }
+
+            let list = std::slice::from_raw_parts(atoms, len as usize)
+                .iter()
+                .map(|&atom| Atom(atom))
+                .collect();
+            XFree(atoms as *mut _);
+            list
+        }
+    }
+
+    /// Sets a Latin-1 `STRING` property. Characters outside Latin-1 are replaced with `?`.
+    pub fn set_string_property(&self, property: Atom, value: &str) {
+        let data = value
+            .chars()
+            .map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' })
+            .collect();
+        self.set_property(
+            property,
+            Atom::STRING,
+            &PropertyData::Format8(data),
+            PropertyMode::Replace,
+        );
+    }
+
+    pub fn get_string_property(&self, property: Atom) -> Result<Option<String>, XError> {
+        Ok(match self.get_property(property, Some(Atom::STRING))? {
+            Some(Property {
+                data: PropertyData::Format8(data),
+                ..
+            }) => Some(data.iter().map(|&c| c as char).collect()),
+            _ => None,
+        })
+    }
+
+    pub fn set_utf8_property(&self, property: Atom, value: &str) {
+        self.set_property(
+            property,
+            _intern(self.display, "UTF8_STRING"),
+            &PropertyData::Format8(value.as_bytes().to_vec()),
+            PropertyMode::Replace,
+        );
+    }
+
+    pub fn get_utf8_property(&self, property: Atom) -> Result<Option<String>, XError> {
+        let utf8_string = _intern(self.display, "UTF8_STRING");
+        Ok(match self.get_property(property, Some(utf8_string))? {
+            Some(Property {
+                data: PropertyData::Format8(data),
+                ..
+            }) => Some(String::from_utf8_lossy(&data).into_owned()),
+            _ => None,
+        })
+    }
+
+    pub fn set_cardinal_property(&self, property: Atom, values: &[u32]) {
+        self.set_property(
+            property,
+            Atom::CARDINAL,
+            &PropertyData::Format32(values.to_vec()),
+            PropertyMode::Replace,
+        );
+    }
+
+    pub fn get_cardinal_property(&self, property: Atom) -> Result<Option<Vec<u32>>, XError> {
+        Ok(match self.get_property(property, Some(Atom::CARDINAL))? {
+            Some(Property {
+                data: PropertyData::Format32(data),
+                ..
+            }) => Some(data),
+            _ => None,
+        })
+    }
+
+    pub fn set_atom_property(&self, property: Atom, values: &[Atom]) {
+        self.set_property(
+            property,
+            Atom::ATOM,
+            &PropertyData::Format32(values.iter().map(|atom| atom.0 as u32).collect()),
+            PropertyMode::Replace,
+        );
+    }
+
+    pub fn get_atom_property(&self, property: Atom) -> Result<Option<Vec<Atom>>, XError> {
+        Ok(match self.get_property(property, Some(Atom::ATOM))? {
+            Some(Property {
+                data: PropertyData::Format32(data),
+                ..
+            }) => Some(data.iter().map(|&atom| Atom(atom as c_ulong)).collect()),
+            _ => None,
+        })
+    }
+
+    pub fn set_window_property(&self, property: Atom, values: &[WindowId]) {
+        self.set_property(
+            property,
+            Atom::WINDOW,
+            &PropertyData::Format32(values.iter().map(|window| window.0 as u32).collect()),
+            PropertyMode::Replace,
+        );
+    }
+
+    pub fn get_window_property(&self, property: Atom) -> Result<Option<Vec<WindowId>>, XError> {
+        Ok(match self.get_property(property, Some(Atom::WINDOW))? {
+            Some(Property {
+                data: PropertyData::Format32(data),
+                ..
+            }) => Some(
+                data.iter()
+                    .map(|&window| WindowId(window as c_ulong))
+                    .collect(),
+            ),
+            _ => None,
+        })
+    }
+
+    /// Makes this window the owner of `selection` and serves `content` to other clients
+    /// from the event loop. Returns `false` if the server did not grant ownership.
+    pub fn own_selection(&self, selection: Atom, content: SelectionContent) -> bool {
+        let time = self.server_time();
+        unsafe {
+            XSetSelectionOwner(self.display, selection.0, self.window, time);
+            if XGetSelectionOwner(self.display, selection.0) != self.window {
+                return false;
+            }
+        }
+
+        let mut selections = SELECTIONS.lock().unwrap();
+        selections.retain(|owned| {
+            !(owned.display == self.display as usize && owned.selection == selection)
+        });
+        selections.push(OwnedSelection {
+            display: self.display as usize,
+            selection,
+            owner: self.window,
+            content,
+            time,
+        });
+        true
+    }
+
+    pub fn release_selection(&self, selection: Atom) {
+        let released = {
+            let mut selections = SELECTIONS.lock().unwrap();
+            selections
+                .iter()
+                .position(|owned| {
+                    owned.display == self.display as usize
+                        && owned.selection == selection
+                        && owned.owner == self.window
+                })
+                .map(|index| selections.remove(index))
+        };
+
+        // With the time we took it at, this is a no-op if another client owns it since
+        if let Some(owned) = released {
+            unsafe { XSetSelectionOwner(self.display, selection.0, 0, owned.time) };
+        }
+    }
+
+    /// Asks the owner of `selection` to convert it to `target` and waits for the answer,
+    /// following the INCR protocol for large transfers.
+    ///
+    /// Returns `None` if there is no owner, the owner refused, or `timeout` elapsed.
+    /// Events for other windows that arrive meanwhile stay queued for the event loop.
+    pub fn convert_selection(
+        &self,
+        selection: Atom,
+        target: Atom,
+        timeout: Duration,
+    ) -> Result<Option<Property>, XError> {
+        let deadline = Instant::now() + timeout;
+        let property = _intern(self.display, "SAFEX_SELECTION");
+        let time = self.server_time();
+
+        unsafe {
+            XConvertSelection(
+                self.display,
+                selection.0,
+                target.0,
+                property.0,
+                self.window,
+                time,
+            );
+        }
+
+        let notify = match _wait_for_event(
+            self.display,
+            EventMatch {
+                type_: SelectionNotify,
+                window: self.window,
+                atom: selection,
+            },
+            deadline,
+        ) {
+            Some(event) => unsafe { event.selection },
+            None => return Ok(None),
+        };
+        if notify.property == 0 {
+            return Ok(None);
+        }
+
+        self.read_selection_reply(property, deadline)
+    }
+
+    // Takes a converted selection out of `property`, following the INCR protocol until
+    // `deadline` if the owner chose it
+    fn read_selection_reply(
+        &self,
+        property: Atom,
+        deadline: Instant,
+    ) -> Result<Option<Property>, XError> {
+        let result = match self.get_property(property, None)? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        if result.type_ != _intern(self.display, "INCR") {
+            self.delete_property(property);
+            return Ok(Some(result));
+        }
+
+        // INCR: every time we delete the property the owner replaces it with the next chunk,
+        // until it writes an empty one
+        let event_mask = _event_mask(self.display, self.window);
+        if event_mask & PropertyChangeMask == 0 {
+            self.select_input(event_mask | PropertyChangeMask);
+        }
+
+        let mut data: Option<Property> = None;
+        self.delete_property(property);
+        let complete = loop {
+            let event = _wait_for_event(
+                self.display,
+                EventMatch {
+                    type_: PropertyNotify,
+                    window: self.window,
+                    atom: property,
+                },
+                deadline,
+            );
+            if event.is_none() {
+                break false;
+            }
+
+            let chunk = match self.get_property(property, None)? {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            self.delete_property(property);
+
+            if chunk.data.is_empty() {
+                break true;
+            }
+
+            data = Some(match data {
+                Some(data) => Property {
+                    type_: data.type_,
+                    data: data.data.append(chunk.data),
+                },
+                None => chunk,
+            });
+        };
+
+        if event_mask & PropertyChangeMask == 0 {
+            self.select_input(event_mask);
+        }
+
+        Ok(if complete { data } else { None })
+    }
+
+    // A server timestamp for the selection requests ICCCM forbids CurrentTime in: appending
+    // nothing to a property still makes the server report a PropertyNotify with its time
+    fn server_time(&self) -> c_ulong {
+        let property = _intern(self.display, "SAFEX_TIMESTAMP");
+        let event_mask = _event_mask(self.display, self.window);
+        if event_mask & PropertyChangeMask == 0 {
+            self.select_input(event_mask | PropertyChangeMask);
+        }
+
+        unsafe {
+            XChangeProperty(
+                self.display,
+                self.window,
+                property.0,
+                Atom::STRING.0,
+                8,
+                PropModeAppend,
+                null(),
+                0,
+            );
+        }
+        let event = _wait_for_event(
+            self.display,
+            EventMatch {
+                type_: PropertyNotify,
+                window: self.window,
+                atom: property,
+            },
+            Instant::now() + Duration::from_secs(1),
+        );
+
+        if event_mask & PropertyChangeMask == 0 {
+            self.select_input(event_mask);
+        }
+        event.map_or(CurrentTime, |event| unsafe { event.property.time })
+    }
+
+    pub fn set_fullscreen(&self, fullscreen: bool) {
+        self.change_wm_state(fullscreen, &["_NET_WM_STATE_FULLSCREEN"]);
+    }
+
+    pub fn set_maximized(&self, maximized: bool) {
+        self.change_wm_state(
+            maximized,
+            &[
+                "_NET_WM_STATE_MAXIMIZED_VERT",
+                "_NET_WM_STATE_MAXIMIZED_HORZ",
+            ],
+        );
+    }
+
+    pub fn set_always_on_top(&self, above: bool) {
+        self.change_wm_state(above, &["_NET_WM_STATE_ABOVE"]);
+    }
+
+    pub fn set_sticky(&self, sticky: bool) {
+        self.change_wm_state(sticky, &["_NET_WM_STATE_STICKY"]);
+    }
+
+    pub fn set_skip_taskbar(&self, skip: bool) {
+        self.change_wm_state(skip, &["_NET_WM_STATE_SKIP_TASKBAR"]);
+    }
+
+    /// Marks the window as modal for its transient-for parent (see [`Window::set_transient_for`]).
+    pub fn set_modal(&self, modal: bool) {
+        self.change_wm_state(modal, &["_NET_WM_STATE_MODAL"]);
+    }
+
+    /// Keeps the window above `parent` and lets the WM treat it as one of its dialogs.
+    pub fn set_transient_for(&self, parent: &WindowRef) {
+        unsafe {
+            XSetTransientForHint(self.display, self.window, parent.window);
+        }
+    }
+
+    pub fn get_transient_for(&self) -> Option<WindowId> {
+        unsafe {
+            let mut parent = 0;
+            (XGetTransientForHint(self.display, self.window, &mut parent) != 0 && parent != 0)
+                .then_some(WindowId(parent))
+        }
+    }
+
+    /// Sets `WM_CLIENT_LEADER` and the `WM_HINTS` window group, so the WM groups this window
+    /// with the leader's other windows, e.g. in the taskbar.
+    pub fn set_client_leader(&self, leader: &WindowRef) {
+        self.set_window_property(
+            _intern(self.display, "WM_CLIENT_LEADER"),
+            &[WindowId(leader.window)],
+        );
+
+        let mut hints = self.get_wm_hints().unwrap_or_default();
+        hints.window_group = Some(WindowId(leader.window));
+        self.set_wm_hints(&hints);
+    }
+
+    pub fn get_client_leader(&self) -> Result<Option<WindowId>, XError> {
+        Ok(self
+            .get_window_property(_intern(self.display, "WM_CLIENT_LEADER"))?
+            .and_then(|windows| windows.first().copied()))
+    }
+
+    /// Iconifies a window the window manager manages, or makes a withdrawn one start out iconic
+    /// when it is mapped.
+    pub fn minimize(&self) {
+        if !self.is_withdrawn() {
+            unsafe {
+                XIconifyWindow(
+                    self.display,
+                    self.window,
+                    _get_screen_number(self.display, self.window),
+                );
+                XFlush(self.display);
+            }
+            return;
+        }
+
+        let mut hints = self.get_wm_hints().unwrap_or_default();
+        hints.initial_state = Some(InitialState::Iconic);
+        self.set_wm_hints(&hints);
+    }
+
+    /// Reads back `_NET_WM_STATE`, which the window manager keeps up to date.
+    /// Listen for `PropertyNotify` on `_NET_WM_STATE` to learn about changes.
+    pub fn get_window_state(&self) -> Result<WindowState, XError> {
+        let atoms = self
+            .get_atom_property(_intern(self.display, "_NET_WM_STATE"))?
+            .unwrap_or_default();
+        let has = |name: &str| atoms.contains(&_intern(self.display, name));
+
+        Ok(WindowState {
+            fullscreen: has("_NET_WM_STATE_FULLSCREEN"),
+            maximized_horizontal: has("_NET_WM_STATE_MAXIMIZED_HORZ"),
+            maximized_vertical: has("_NET_WM_STATE_MAXIMIZED_VERT"),
+            minimized: has("_NET_WM_STATE_HIDDEN"),
+            above: has("_NET_WM_STATE_ABOVE"),
+            sticky: has("_NET_WM_STATE_STICKY"),
+            skip_taskbar: has("_NET_WM_STATE_SKIP_TASKBAR"),
+            modal: has("_NET_WM_STATE_MODAL"),
+        })
+    }
+
+    /// Turns all window manager decorations on or off, keeping the allowed functions.
+    pub fn set_decorations(&self, decorations: bool) {
+        let mut hints = self.get_motif_hints().ok().flatten().unwrap_or_default();
+        hints.decorations = Some(if decorations {
+            MotifDecorations::ALL
+        } else {
+            MotifDecorations::NONE
+        });
+        self.set_motif_hints(&hints);
+    }
+
+    pub fn set_motif_hints(&self, hints: &MotifHints) {
+        // Layout: flags, functions, decorations, input mode, status
+        let mut data = [0u32; 5];
+
+        if let Some(functions) = hints.functions {
+            data[0] |= MWM_HINTS_FUNCTIONS;
+            data[1] = if functions == MotifFunctions::ALL {
+                MWM_FUNC_ALL
+            } else {
+                _flag_bits(&[
+                    (functions.resize, MWM_FUNC_RESIZE),
+                    (functions.move_, MWM_FUNC_MOVE),
+                    (functions.minimize, MWM_FUNC_MINIMIZE),
+                    (functions.maximize, MWM_FUNC_MAXIMIZE),
+                    (functions.close, MWM_FUNC_CLOSE),
+                ])
+            };
+        }
+        if let Some(decorations) = hints.decorations {
+            data[0] |= MWM_HINTS_DECORATIONS;
+            data[2] = if decorations == MotifDecorations::ALL {
+                MWM_DECOR_ALL
+            } else {
+                _flag_bits(&[
+                    (decorations.border, MWM_DECOR_BORDER),
+                    (decorations.resize_handles, MWM_DECOR_RESIZEH),
+                    (decorations.title, MWM_DECOR_TITLE),
+                    (decorations.menu, MWM_DECOR_MENU),
+                    (decorations.minimize_button, MWM_DECOR_MINIMIZE),
+                    (decorations.maximize_button, MWM_DECOR_MAXIMIZE),
+                ])
+            };
+        }
+
+        let property = _intern(self.display, "_MOTIF_WM_HINTS");
+        self.set_property(
+            property,
+            property,
+            &PropertyData::Format32(data.to_vec()),
+            PropertyMode::Replace,
+        );
+    }
+
+    pub fn get_motif_hints(&self) -> Result<Option<MotifHints>, XError> {
+        let property = _intern(self.display, "_MOTIF_WM_HINTS");
+        let data = match self.get_property(property, Some(property))? {
+            Some(Property {
+                data: PropertyData::Format32(data),
+                ..
+            }) if data.len() >= 3 => data,
+            _ => return Ok(None),
+        };
+
+        // With the ALL bit set, the remaining bits list what is removed rather than added
+        let function = |bit: u32| (data[1] & bit != 0) != (data[1] & MWM_FUNC_ALL != 0);
+        let decoration = |bit: u32| (data[2] & bit != 0) != (data[2] & MWM_DECOR_ALL != 0);
+
+        Ok(Some(MotifHints {
+            functions: (data[0] & MWM_HINTS_FUNCTIONS != 0).then(|| MotifFunctions {
+                resize: function(MWM_FUNC_RESIZE),
+                move_: function(MWM_FUNC_MOVE),
+                minimize: function(MWM_FUNC_MINIMIZE),
+                maximize: function(MWM_FUNC_MAXIMIZE),
+                close: function(MWM_FUNC_CLOSE),
+            }),
+            decorations: (data[0] & MWM_HINTS_DECORATIONS != 0).then(|| MotifDecorations {
+                border: decoration(MWM_DECOR_BORDER),
+                resize_handles: decoration(MWM_DECOR_RESIZEH),
+                title: decoration(MWM_DECOR_TITLE),
+                menu: decoration(MWM_DECOR_MENU),
+                minimize_button: decoration(MWM_DECOR_MINIMIZE),
+                maximize_button: decoration(MWM_DECOR_MAXIMIZE),
+            }),
+        }))
+    }
+
+    /// Sets `_NET_WM_WINDOW_TYPE`. Window managers only read it when the window is mapped.
+    pub fn set_window_type(&self, window_type: WindowType) {
+        self.set_atom_property(
+            _intern(self.display, "_NET_WM_WINDOW_TYPE"),
+            &[_intern(self.display, window_type.atom_name())],
+        );
+    }
+
+    /// Returns the first type in `_NET_WM_WINDOW_TYPE` that safex knows about.
+    pub fn get_window_type(&self) -> Result<Option<WindowType>, XError> {
+        let atoms = self
+            .get_atom_property(_intern(self.display, "_NET_WM_WINDOW_TYPE"))?
+            .unwrap_or_default();
+
+        Ok(atoms.iter().find_map(|atom| {
+            WindowType::ALL
+                .into_iter()
+                .find(|window_type| _intern(self.display, window_type.atom_name()) == *atom)
+        }))
+    }
+
+    // ICCCM: a window is withdrawn until the window manager sets WM_STATE on it, and again
+    // once that says WithdrawnState. Unlike map_state this counts iconified windows as managed.
+    fn is_withdrawn(&self) -> bool {
+        let wm_state = _intern(self.display, "WM_STATE");
+        match self.get_property(wm_state, Some(wm_state)) {
+            Ok(Some(Property {
+                data: PropertyData::Format32(data),
+                ..
+            })) => data
+                .first()
+                // WithdrawnState, which x11-rs doesn't export
+                .is_none_or(|&state| state == 0),
+            _ => true,
+        }
+    }
+
+    // Once managed, _NET_WM_STATE belongs to the WM and changes have to be requested from it;
+    // before that, the property is read by the WM when the window is mapped.
+    fn change_wm_state(&self, add: bool, states: &[&str]) {
+        let states: Vec<Atom> = states
+            .iter()
+            .map(|state| _intern(self.display, state))
+            .collect();
+        let net_wm_state = _intern(self.display, "_NET_WM_STATE");
+
+        if !self.is_withdrawn() {
+            // _NET_WM_STATE_REMOVE = 0, _NET_WM_STATE_ADD = 1; source 1 is a normal application
+            _send_client_message(
+                self.display,
+                unsafe { XRootWindow(self.display, _get_screen_number(self.display, self.window)) },
+                self.window,
+                net_wm_state,
+                [
+                    add as c_long,
+                    states[0].0 as c_long,
+                    states.get(1).map_or(0, |state| state.0 as c_long),
+                    1,
+                    0,
+                ],
+                SubstructureRedirectMask | SubstructureNotifyMask,
+            );
+            return;
+        }
+
+        let mut current = self
+            .get_atom_property(net_wm_state)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        current.retain(|state| !states.contains(state));
+        if add {
+            current.extend(states);
+        }
+        self.set_atom_property(net_wm_state, &current);
+    }
+
+    pub fn set_size_hints(&self, hints: &SizeHints) {
+        unsafe {
+            let mut raw: XSizeHints = std::mem::zeroed();
+
+            if let Some((x, y)) = hints.position {
+                raw.flags |= if hints.user_position {
+                    USPosition
+                } else {
+                    PPosition
+                };
+                raw.x = x;
+                raw.y = y;
+            }
+            if let Some((width, height)) = hints.size {
+                raw.flags |= if hints.user_size { USSize } else { PSize };
+                raw.width = width as c_int;
+                raw.height = height as c_int;
+            }
+            if let Some((width, height)) = hints.min_size {
+                raw.flags |= PMinSize;
+                raw.min_width = width as c_int;
+                raw.min_height = height as c_int;
+            }
+            if let Some((width, height)) = hints.max_size {
+                raw.flags |= PMaxSize;
+                raw.max_width = width as c_int;
+                raw.max_height = height as c_int;
+            }
+            if let Some((width, height)) = hints.resize_increments {
+                raw.flags |= PResizeInc;
+                raw.width_inc = width as c_int;
+                raw.height_inc = height as c_int;
+            }
+            if let Some(((min_x, min_y), (max_x, max_y))) = hints.aspect {
+                raw.flags |= PAspect;
+                raw.min_aspect = AspectRatio { x: min_x, y: min_y };
+                raw.max_aspect = AspectRatio { x: max_x, y: max_y };
+            }
+            if let Some((width, height)) = hints.base_size {
+                raw.flags |= PBaseSize;
+                raw.base_width = width as c_int;
+                raw.base_height = height as c_int;
+            }
+            if let Some(gravity) = hints.gravity {
+                raw.flags |= PWinGravity;
+                raw.win_gravity = gravity;
+            }
+
+            XSetWMNormalHints(self.display, self.window, &mut raw);
+        }
+    }
+
+    pub fn get_size_hints(&self) -> Option<SizeHints> {
+        unsafe {
+            let mut raw: XSizeHints = std::mem::zeroed();
+            let mut supplied = 0;
+            if XGetWMNormalHints(self.display, self.window, &mut raw, &mut supplied) == 0 {
+                return None;
+            }
+
+            let flags = raw.flags;
+            let has = |flag: c_long| flags & flag != 0;
+            let pair = |flag: c_long, width: c_int, height: c_int| {
+                has(flag).then_some((width as u32, height as u32))
+            };
+
+            Some(SizeHints {
+                position: (has(USPosition) || has(PPosition)).then_some((raw.x, raw.y)),
+                user_position: has(USPosition),
+                size: (has(USSize) || has(PSize)).then_some((raw.width as u32, raw.height as u32)),
+                user_size: has(USSize),
+                min_size: pair(PMinSize, raw.min_width, raw.min_height),
+                max_size: pair(PMaxSize, raw.max_width, raw.max_height),
+                resize_increments: pair(PResizeInc, raw.width_inc, raw.height_inc),
+                aspect: has(PAspect).then_some((
+                    (raw.min_aspect.x, raw.min_aspect.y),
+                    (raw.max_aspect.x, raw.max_aspect.y),
+                )),
+                base_size: pair(PBaseSize, raw.base_width, raw.base_height),
+                gravity: has(PWinGravity).then_some(raw.win_gravity),
+            })
+        }
+    }
+
+    /// Sets `_NET_WM_ICON` from one or more images; the window manager picks the best size.
+    pub fn set_icon(&self, icons: &[Icon]) {
+        let property = _intern(self.display, "_NET_WM_ICON");
+        if icons.is_empty() {
+            self.delete_property(property);
+            return;
+        }
+
+        let mut data = Vec::new();
+        for icon in icons {
+            data.push(icon.width);
+            data.push(icon.height);
+            data.extend(
+                icon.pixels()
+                    .map(|[r, g, b, a]| u32::from_be_bytes([a, r, g, b])),
+            );
+        }
+        self.set_cardinal_property(property, &data);
+    }
+
+    /// Sets the `WM_HINTS` icon pixmap and mask for window managers without `_NET_WM_ICON`
+    /// support. The pixmaps belong to this `WindowRef` and are freed when it is dropped or
+    /// the icon is replaced.
+    pub fn set_icon_pixmap(&self, icon: &Icon) -> Result<(), XError> {
+        let display = self.display;
+        let (pixmap, mask) = _checked(display, || unsafe {
+            let mut attributes: XWindowAttributes = std::mem::zeroed();
+            XGetWindowAttributes(display, self.window, &mut attributes);
+            let visual = &*attributes.visual;
+
+            let pixmap = XCreatePixmap(
+                display,
+                self.window,
+                icon.width,
+                icon.height,
+                attributes.depth as c_uint,
+            );
+            let image = XCreateImage(
+                display,
+                attributes.visual,
+                attributes.depth as c_uint,
+                ZPixmap,
+                0,
+                null_mut(),
+                icon.width,
+                icon.height,
+                32,
+                0,
+            );
+            // Client side allocations fail without raising a protocol error
+            let alloc_failed = |description: &str| XError {
+                error_code: BadAlloc,
+                request_code: 0,
+                minor_code: 0,
+                resource_id: 0,
+                serial: 0,
+                description: String::from(description),
+            };
+            if image.is_null() {
+                XFreePixmap(display, pixmap);
+                return Err(alloc_failed("XCreateImage returned NULL"));
+            }
+            (*image).data =
+                libc::malloc(((*image).bytes_per_line * (*image).height) as usize) as *mut c_char;
+            if (*image).data.is_null() {
+                XDestroyImage(image);
+                XFreePixmap(display, pixmap);
+                return Err(alloc_failed("could not allocate the icon image"));
+            }
+
+            // X bitmaps are stored LSB first, each row padded to a whole byte
+            let stride = (icon.width as usize).div_ceil(8);
+            let mut bits = vec![0u8; stride * icon.height as usize];
+
+            for (i, [r, g, b, a]) in icon.pixels().enumerate() {
+                let (x, y) = (i % icon.width as usize, i / icon.width as usize);
+                let pixel = _scale_channel(r, visual.red_mask)
+                    | _scale_channel(g, visual.green_mask)
+                    | _scale_channel(b, visual.blue_mask);
+                XPutPixel(image, x as c_int, y as c_int, pixel);
+                if a >= 0x80 {
+                    bits[y * stride + x / 8] |= 1 << (x % 8);
+                }
+            }
+
+            let gc = XCreateGC(display, pixmap, 0, null_mut());
+            XPutImage(
+                display,
+                pixmap,
+                gc,
+                image,
+                0,
+                0,
+                0,
+                0,
+                icon.width,
+                icon.height,
+            );
+            XFreeGC(display, gc);
+            XDestroyImage(image);
+
+            let mask = XCreateBitmapFromData(
+                display,
+                self.window,
+                bits.as_ptr() as *const c_char,
+                icon.width,
+                icon.height,
+            );
+            Ok((pixmap, mask))
+        })??;
+
+        let mut hints = self.get_wm_hints().unwrap_or_default();
+        hints.icon_pixmap = Some(pixmap);
+        hints.icon_mask = Some(mask);
+        self.set_wm_hints(&hints);
+
+        // The hints point at the new pair now, so the old one can go
+        let owned = |pixmap| PixMap {
+            pixmap,
+            display,
+            owned: true,
+            _display: PhantomData,
+        };
+        self.icon.replace(Some((owned(pixmap), owned(mask))));
+        Ok(())
+    }
+
+    pub fn set_wm_hints(&self, hints: &WmHints) {
+        unsafe {
+            let mut raw: XWMHints = std::mem::zeroed();
+
+            if let Some(input) = hints.input {
+                raw.flags |= InputHint;
+                raw.input = input as Bool;
+            }
+            if let Some(state) = hints.initial_state {
+                raw.flags |= StateHint;
+                raw.initial_state = match state {
+                    InitialState::Normal => NORMAL_STATE,
+                    InitialState::Iconic => ICONIC_STATE,
+                };
+            }
+            if let Some(pixmap) = hints.icon_pixmap {
+                raw.flags |= IconPixmapHint;
+                raw.icon_pixmap = pixmap;
+            }
+            if let Some(mask) = hints.icon_mask {
+                raw.flags |= IconMaskHint;
+                raw.icon_mask = mask;
+            }
+            if let Some(group) = hints.window_group {
+                raw.flags |= WindowGroupHint;
+                raw.window_group = group.0;
+            }
+            if hints.urgent {
+                raw.flags |= XUrgencyHint;
+            }
+
+            XSetWMHints(self.display, self.window, &mut raw);
+        }
+    }
+
+    pub fn get_wm_hints(&self) -> Option<WmHints> {
+        unsafe {
+            let raw = XGetWMHints(self.display, self.window);
+            if raw.is_null() {
+                return None;
+            }
+
+            let flags = (*raw).flags;
+            let has = |flag: c_long| flags & flag != 0;
+            let hints = WmHints {
+                input: has(InputHint).then_some((*raw).input != 0),
+                initial_state: has(StateHint).then_some(match (*raw).initial_state {
+                    ICONIC_STATE => InitialState::Iconic,
+                    _ => InitialState::Normal,
+                }),
+                icon_pixmap: has(IconPixmapHint).then_some((*raw).icon_pixmap),
+                icon_mask: has(IconMaskHint).then_some((*raw).icon_mask),
+                window_group: has(WindowGroupHint).then_some(WindowId((*raw).window_group)),
+                urgent: has(XUrgencyHint),
+            };
+            XFree(raw as *mut _);
+
+            Some(hints)
+        }
+    }
+
+    pub fn set_class_hint(&self, hint: &ClassHint) {
+        // C strings end at the first NUL, so drop any instead of failing
+        let instance = CString::new(hint.instance.replace('\0', "")).unwrap();
+        let class = CString::new(hint.class.replace('\0', "")).unwrap();
+        unsafe {
+            let mut raw = XClassHint {
+                res_name: instance.as_ptr() as *mut c_char,
+                res_class: class.as_ptr() as *mut c_char,
+            };
+            XSetClassHint(self.display, self.window, &mut raw);
+        }
+    }
+
+    pub fn get_class_hint(&self) -> Option<ClassHint> {
+        unsafe {
+            let mut raw = XClassHint {
+                res_name: null_mut(),
+                res_class: null_mut(),
+            };
+            if XGetClassHint(self.display, self.window, &mut raw) == 0 {
+                return None;
+            }
+
+            let take = |string: *mut c_char| {
+                if string.is_null() {
+                    return String::new();
+                }
+                let owned = CStr::from_ptr(string).to_string_lossy().into_owned();
+                XFree(string as *mut _);
+                owned
+            };
+
+            Some(ClassHint {
+                instance: take(raw.res_name),
+                class: take(raw.res_class),
+            })
+        }
+    }
+
+    /// Advertises (or stops advertising) XDND support so files can be dropped on the window.
+    pub fn set_drop_target(&self, enabled: bool) {
+        let xdnd_aware = _intern(self.display, "XdndAware");
+        if enabled {
+            self.set_atom_property(xdnd_aware, &[Atom(XDND_VERSION as c_ulong)]);
+        } else {
+            self.delete_property(xdnd_aware);
+        }
+    }
+
+    /// Runs an XDND drag with `content` until the mouse button is released or Escape is pressed.
+    /// Must be called while a button is held, typically from a `ButtonPress` or `MotionNotify`.
+    ///
+    /// Returns `true` if a target accepted the drop and reported it finished.
+    #[allow(non_upper_case_globals)]
+    pub fn start_drag(&self, content: SelectionContent) -> Result<bool, XError> {
+        let display = self.display;
+        let xdnd_selection = _intern(display, "XdndSelection");
+        let xdnd_type_list = _intern(display, "XdndTypeList");
+        let action_copy = _intern(display, "XdndActionCopy");
+
+        let types: Vec<Atom> = content
+            .targets
+            .iter()
+            .map(|(target, _, _)| *target)
+            .collect();
+        if types.len() > 3 {
+            self.set_atom_property(xdnd_type_list, &types);
+        }
+        if !self.own_selection(xdnd_selection, content) {
+            return Ok(false);
+        }
+
+        let grabbed = unsafe {
+            XGrabPointer(
+                display,
+                self.window,
+                False,
+                (ButtonReleaseMask | PointerMotionMask) as c_uint,
+                GrabModeAsync,
+                GrabModeAsync,
+                0,
+                0,
+                CurrentTime,
+            )
+        };
+        if grabbed != GrabSuccess {
+            self.release_selection(xdnd_selection);
+            return Ok(false);
+        }
+        unsafe {
+            XGrabKeyboard(
+                display,
+                self.window,
+                False,
+                GrabModeAsync,
+                GrabModeAsync,
+                CurrentTime,
+            );
+        }
+
+        let mut target: Option<XdndTarget> = None;
+        // What each top-level window under the pointer had to offer, looked up once per drag
+        let mut toplevels: BTreeMap<c_ulong, Option<XdndTarget>> = BTreeMap::new();
+        let mut accepted = false;
+        let mut finished = false;
+        let mut dropped = false;
+
+        loop {
+            let mut event = unsafe { _next_drag_event(display, self.window, None) };
+            match event.get_type() {
+                MotionNotify => {
+                    let motion = unsafe { event.motion };
+                    let mut x = 0;
+                    let mut y = 0;
+                    let mut toplevel = 0;
+                    unsafe {
+                        XTranslateCoordinates(
+                            display,
+                            motion.root,
+                            motion.root,
+                            motion.x_root,
+                            motion.y_root,
+                            &mut x,
+                            &mut y,
+                            &mut toplevel,
+                        );
+                    }
+                    let under = match toplevel {
+                        0 => None,
+                        toplevel => *toplevels
+                            .entry(toplevel)
+                            .or_insert_with(|| _find_xdnd_target(display, toplevel)),
+                    };
+
+                    if under.map(|under| under.window) != target.map(|target| target.window) {
+                        if let Some(target) = target {
+                            target.send(display, "XdndLeave", [self.window as c_long, 0, 0, 0, 0]);
+                        }
+                        if let Some(under) = under {
+                            let more_types = (types.len() > 3) as c_long;
+                            let mut data = [
+                                self.window as c_long,
+                                (under.version << 24) | more_types,
+                                0,
+                                0,
+                                0,
+                            ];
+                            for (slot, type_) in data[2..].iter_mut().zip(&types) {
+                                *slot = type_.0 as c_long;
+                            }
+                            under.send(display, "XdndEnter", data);
+                        }
+                        target = under;
+                        accepted = false;
+                    }
+
+                    if let Some(target) = target {
+                        target.send(
+                            display,
+                            "XdndPosition",
+                            [
+                                self.window as c_long,
+                                0,
+                                ((motion.x_root as c_long) << 16)
+                                    | (motion.y_root as c_long & 0xffff),
+                                motion.time as c_long,
+                                action_copy.0 as c_long,
+                            ],
+                        );
+                    }
+                }
+                ClientMessage => {
+                    let message = unsafe { event.client_message };
+                    if Atom(message.message_type) == _intern(display, "XdndStatus")
+                        && target.map(|target| target.window as c_long)
+                            == Some(message.data.get_long(0))
+                    {
+                        accepted = message.data.get_long(1) & 1 != 0;
+                    }
+                }
+                SelectionRequest => _serve_selection_request(unsafe { &event.selection_request }),
+                KeyPress
+                    if unsafe { XLookupKeysym(&mut event.key, 0) }
+                        == x11::keysym::XK_Escape as KeySym =>
+                {
+                    break;
+                }
+                ButtonRelease => {
+                    if let (Some(target), true) = (target, accepted) {
+                        target.send(
+                            display,
+                            "XdndDrop",
+                            [
+                                self.window as c_long,
+                                0,
+                                unsafe { event.button.time } as c_long,
+                                0,
+                                0,
+                            ],
+                        );
+                        dropped = true;
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        unsafe {
+            XUngrabPointer(display, CurrentTime);
+            XUngrabKeyboard(display, CurrentTime);
+        }
+
+        match (target, dropped) {
+            (Some(target), false) => {
+                target.send(display, "XdndLeave", [self.window as c_long, 0, 0, 0, 0]);
+            }
+            (Some(_), true) => {
+                // Keep serving the selection until the target says it is done with it
+                let deadline = Instant::now() + Duration::from_secs(5);
+                while Instant::now() < deadline {
+                    let event = unsafe { _next_drag_event(display, self.window, Some(deadline)) };
+                    match event.get_type() {
+                        SelectionRequest => {
+                            _serve_selection_request(unsafe { &event.selection_request })
+                        }
+                        ClientMessage => {
+                            let message = unsafe { event.client_message };
+                            if Atom(message.message_type) == _intern(display, "XdndFinished") {
+                                finished = message.data.get_long(1) & 1 != 0;
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            (None, _) => {}
+        }
+
+        self.release_selection(xdnd_selection);
+        if types.len() > 3 {
+            self.delete_property(xdnd_type_list);
+        }
+
+        Ok(finished)
+    }
+
+    /// Fetches `selection` as text, preferring `UTF8_STRING` over Latin-1 `STRING`.
+    pub fn get_selection_text(
+        &self,
+        selection: Atom,
+        timeout: Duration,
+    ) -> Result<Option<String>, XError> {
+        // One deadline for both attempts, so a silent owner costs `timeout` and not twice that
+        let deadline = Instant::now() + timeout;
+        let utf8_string = _intern(self.display, "UTF8_STRING");
+        for target in [utf8_string, Atom::STRING] {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if let Some(Property {
+                type_,
+                data: PropertyData::Format8(data),
+            }) = self.convert_selection(selection, target, remaining)?
+            {
+                return Ok(Some(if type_ == Atom::STRING {
+                    data.iter().map(|&c| c as char).collect()
+                } else {
+                    String::from_utf8_lossy(&data).into_owned()
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn id(&self) -> WindowId {
+        WindowId(self.window)
+    }
+
+    /// Routes key presses through an X input method, so dead keys and IMEs such as fcitx or
+    /// ibus produce composed text. If the input method does not support `style`, simpler
+    /// styles are tried; returns the style in use, or `None` if no input method is available.
+    ///
+    /// The input method follows the locale and `XMODIFIERS`. If the program has left `LC_CTYPE`
+    /// at "C", it is set from the environment first.
+    pub fn enable_input_method(&self, style: PreeditStyle) -> Option<PreeditStyle> {
+        self.disable_input_method();
+
+        unsafe {
+            let im = _open_input_method(self.display)?;
+            let supported = _supported_input_styles(im);
+            let candidates: &[PreeditStyle] = match style {
+                PreeditStyle::Callbacks => &[
+                    PreeditStyle::Callbacks,
+                    PreeditStyle::Root,
+                    PreeditStyle::NoPreedit,
+                ],
+                PreeditStyle::OverTheSpot => &[
+                    PreeditStyle::OverTheSpot,
+                    PreeditStyle::Root,
+                    PreeditStyle::NoPreedit,
+                ],
+                PreeditStyle::Root => &[PreeditStyle::Root, PreeditStyle::NoPreedit],
+                PreeditStyle::NoPreedit => &[PreeditStyle::NoPreedit],
+            };
+            let style = candidates
+                .iter()
+                .copied()
+                .find(|style| supported.contains(&style.as_raw()))?;
+
+            let client = Box::new((self.display as usize, self.window));
+            let client_data = &*client as *const (usize, c_ulong) as XPointer;
+            let ic = match style {
+                PreeditStyle::Callbacks => {
+                    // Preedit start returns the maximum preedit length, unlike the other callbacks
+                    let start = XIMCallback {
+                        client_data,
+                        callback: Some(std::mem::transmute::<
+                            unsafe extern "C" fn(XIM, XPointer, XPointer) -> c_int,
+                            unsafe extern "C" fn(XIM, XPointer, XPointer),
+                        >(_preedit_start)),
+                    };
+                    let done = XIMCallback {
+                        client_data,
+                        callback: Some(_preedit_done),
+                    };
+                    let draw = XIMCallback {
+                        client_data,
+                        callback: Some(_preedit_draw),
+                    };
+                    let caret = XIMCallback {
+                        client_data,
+                        callback: Some(_preedit_caret),
+                    };
+                    let attributes = XVaCreateNestedList(
+                        0,
+                        XNPreeditStartCallback_0.as_ptr(),
+                        &start as *const XIMCallback,
+                        XNPreeditDoneCallback_0.as_ptr(),
+                        &done as *const XIMCallback,
+                        XNPreeditDrawCallback_0.as_ptr(),
+                        &draw as *const XIMCallback,
+                        XNPreeditCaretCallback_0.as_ptr(),
+                        &caret as *const XIMCallback,
+                        null_mut::<c_void>(),
+                    );
+                    let ic = _create_input_context(im, style, self.window, attributes);
+                    XFree(attributes);
+                    ic
+                }
+                PreeditStyle::OverTheSpot => {
+                    let spot = XPoint { x: 0, y: 0 };
+                    let attributes = XVaCreateNestedList(
+                        0,
+                        XNSpotLocation_0.as_ptr(),
+                        &spot as *const XPoint,
+                        null_mut::<c_void>(),
+                    );
+                    let ic = _create_input_context(im, style, self.window, attributes);
+                    XFree(attributes);
+                    ic
+                }
+                PreeditStyle::Root | PreeditStyle::NoPreedit => {
+                    _create_input_context(im, style, self.window, null_mut())
+                }
+            };
+            if ic.is_null() {
+                return None;
+            }
+
+            INPUT_CONTEXTS.lock().unwrap().push(InputContext {
+                display: self.display as usize,
+                window: self.window,
+                ic: ic as usize,
+                _client: client,
+                preedit: Vec::new(),
+                caret: 0,
+            });
+
+            // There will be no FocusIn for a window that already has the focus
+            let (mut focus, mut revert_to) = (0, 0);
+            XGetInputFocus(self.display, &mut focus, &mut revert_to);
+            if focus == self.window {
+                XSetICFocus(ic);
+            }
+
+            Some(style)
+        }
+    }
+
+    pub fn disable_input_method(&self) {
+        let removed: Vec<InputContext> = {
+            let mut contexts = INPUT_CONTEXTS.lock().unwrap();
+            let (removed, kept) = contexts
+                .drain(..)
+                .partition(|context| context.is(self.display, self.window));
+            *contexts = kept;
+            removed
+        };
+
+        // Destroying the context can run the preedit callbacks, which take the lock
+        for context in removed {
+            unsafe { XDestroyIC(context.ic as XIC) };
+        }
+    }
+
+    /// Tells the input method where the text cursor is, in window coordinates, so it can place
+    /// its candidate window or, with [`PreeditStyle::OverTheSpot`], the composition text.
+    pub fn set_ime_spot(&self, x: i16, y: i16) {
+        let Some(ic) = _input_context(self.display, self.window) else {
+            return;
+        };
+
+        unsafe {
+            let spot = XPoint { x, y };
+            let attributes = XVaCreateNestedList(
+                0,
+                XNSpotLocation_0.as_ptr(),
+                &spot as *const XPoint,
+                null_mut::<c_void>(),
+            );
+            XSetICValues(
+                ic,
+                XNPreeditAttributes_0.as_ptr(),
+                attributes,
+                null_mut::<c_void>(),
+            );
+            XFree(attributes);
+        }
+    }
+
+    /// Runs an event loop for this window alone until `func` sets [`ControlFlow::Exit`].
+    /// Events for other windows are dropped; use [`EventLoop`] to drive several windows.
+    pub fn run<F>(&self, mut func: F)
+    where
+        F: FnMut(WindowEvent, &mut ControlFlow),
+    {
+        _run_event_loop(self.display, |window, event, control_flow| {
+            if window.is_none_or(|window| window.0 == self.window) {
+                func(event, control_flow);
+            }
+        });
+    }
+
+    /// Queues a [`WindowEvent::RedrawRequested`] for the next time the event loop runs dry.
+    pub fn request_redraw(&self) {
+        let mut requests = REDRAW_REQUESTS.lock().unwrap();
+        let request = (self.display as usize, self.window);
+        if !requests.contains(&request) {
+            requests.push(request);
+        }
+    }
+
+    // Drops what safex keeps for the window in its registries
+    fn release(&self) {
+        let display = self.display as usize;
+        SELECTIONS
+            .lock()
+            .unwrap()
+            .retain(|owned| !(owned.display == display && owned.owner == self.window));
+        INCR_TRANSFERS
+            .lock()
+            .unwrap()
+            .retain(|transfer| !(transfer.display == display && transfer.requestor == self.window));
+        XDND_DRAGS
+            .lock()
+            .unwrap()
+            .retain(|drag| !(drag.display == display && drag.target == self.window));
+        REDRAW_REQUESTS
+            .lock()
+            .unwrap()
+            .retain(|request| *request != (display, self.window));
+        PENDING_EVENTS
+            .lock()
+            .unwrap()
+            .retain(|(event_display, window, _)| {
+                !(*event_display == display && *window == self.window)
+            });
+        self.disable_input_method();
+
+        unsafe {
+            _free_back_buffer(self.display, self.window);
+        }
+    }
+}
+
+impl Drop for Window<'_> {
+    fn drop(&mut self) {
+        self.window.release();
+        unsafe {
+            XDestroyWindow(self.window.display, self.window.window);
+        }
+    }
+}
+
+impl Drop for ForeignWindow<'_> {
+    fn drop(&mut self) {
+        self.window.release();
+    }
+}
+
+impl<'d> Deref for Window<'d> {
+    type Target = WindowRef<'d>;
+
+    fn deref(&self) -> &WindowRef<'d> {
+        &self.window
+    }
+}
+
+impl<'d> Deref for ForeignWindow<'d> {
+    type Target = WindowRef<'d>;
+
+    fn deref(&self) -> &WindowRef<'d> {
+        &self.window
+    }
+}
+
+impl AsRaw<c_ulong> for WindowRef<'_> {
+    fn as_raw(&self) -> c_ulong {
+        self.window
+    }
+}
+
+impl AsRaw<c_ulong> for Window<'_> {
+    fn as_raw(&self) -> c_ulong {
+        self.window.window
+    }
+}
+
+impl AsRaw<c_ulong> for ForeignWindow<'_> {
+    fn as_raw(&self) -> c_ulong {
+        self.window.window
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XError {
+    pub error_code: ErrorCode,
+    pub request_code: u8,
+    pub minor_code: u8,
+    pub resource_id: c_ulong,
+    pub serial: c_ulong,
+    pub description: String,
+}
+
+impl fmt::Display for XError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (request {}.{}, resource 0x{:x}, serial {})",
+            self.description, self.request_code, self.minor_code, self.resource_id, self.serial
+        )
+    }
+}
+
+impl std::error::Error for XError {}
+
+// Atoms never change for the lifetime of a connection, so both directions are cached per display
+struct AtomCache {
+    atoms: BTreeMap<(usize, String), Atom>,
+    names: BTreeMap<(usize, Atom), String>,
+}
+
+impl AtomCache {
+    fn insert(&mut self, key: (usize, String), atom: Atom) {
+        self.names.insert((key.0, atom), key.1.clone());
+        self.atoms.insert(key, atom);
+    }
+
+    fn forget(&mut self, display: usize) {
+        self.atoms.retain(|(owner, _), _| *owner != display);
+        self.names.retain(|(owner, _), _| *owner != display);
+    }
+}
+
+struct OwnedSelection {
+    display: usize,
+    selection: Atom,
+    owner: c_ulong,
+    content: SelectionContent,
+    // The server time ownership was taken at
+    time: c_ulong,
+}
+
+// An outgoing selection transfer that is too large for one request
+struct IncrTransfer {
+    display: usize,
+    requestor: c_ulong,
+    property: Atom,
+    type_: Atom,
+    data: Vec<u8>,
+    offset: usize,
+    // The requestor's event mask before we added PropertyChangeMask
+    event_mask: Mask,
+}
+
+// What a window created with a back buffer draws to: a DBE back buffer when `swap_action`
+// is set, otherwise a pixmap that has to follow the window size
+struct BackBuffer {
+    display: usize,
+    window: c_ulong,
+    drawable: c_ulong,
+    width: u32,
+    height: u32,
+    depth: u32,
+    background: c_ulong,
+    preserve: bool,
+    #[cfg(feature = "dbe")]
+    swap_action: Option<SwapAction>,
+}
+
+type SharedBackBuffer = std::sync::Arc<Mutex<BackBuffer>>;
+
+impl BackBuffer {
+    fn is(&self, display: *mut x11::xlib::Display, window: c_ulong) -> bool {
+        self.display == display as usize && self.window == window
+    }
+
+    #[cfg(feature = "dbe")]
+    fn is_dbe(&self) -> bool {
+        self.swap_action.is_some()
+    }
+
+    #[cfg(not(feature = "dbe"))]
+    fn is_dbe(&self) -> bool {
+        false
+    }
+}
+
+// A window's input context, see `WindowRef::enable_input_method`
+struct InputContext {
+    display: usize,
+    window: c_ulong,
+    ic: usize,
+    // The (display, window) pair the preedit callbacks get a pointer to
+    _client: Box<(usize, c_ulong)>,
+    preedit: Vec<char>,
+    caret: usize,
+}
+
+impl InputContext {
+    fn is(&self, display: *mut x11::xlib::Display, window: c_ulong) -> bool {
+        self.display == display as usize && self.window == window
+    }
+}
+
+// The layout XGetIMValues returns for XNQueryInputStyle; x11-rs doesn't declare it
+#[repr(C)]
+struct XIMStyles {
+    count_styles: c_ushort,
+    supported_styles: *mut c_ulong,
+}
+
+// An XDND drag currently hovering over one of our windows
+struct XdndDrag {
+    display: usize,
+    target: c_ulong,
+    source: c_ulong,
+    types: Vec<Atom>,
+    paths: Option<Vec<PathBuf>>,
+    // The paths were asked for; XdndStatus waits until they arrive
+    requested: bool,
+    // The drop came in while the paths were on their way, they finish it
+    dropped: bool,
+}
+
+// A window a drag of ours can be dropped on. Messages go to `proxy`, which is the window
+// itself unless it named another one in XdndProxy.
+#[derive(Clone, Copy)]
+struct XdndTarget {
+    window: c_ulong,
+    proxy: c_ulong,
+    version: c_long,
+}
+
+impl XdndTarget {
+    fn send(&self, display: *mut x11::xlib::Display, message_type: &str, data: [c_long; 5]) {
+        let message_type = _intern(display, message_type);
+        _send_client_message(
+            display,
+            self.proxy,
+            self.window,
+            message_type,
+            data,
+            NoEventMask,
+        );
+    }
+}
+
+const XDND_VERSION: c_long = 5;
+
+#[derive(Debug, PartialEq, Eq)]
+enum DisplaySocket {
+    Unix(PathBuf),
+    Tcp(String, u16),
+}
+
+struct EventMatch {
+    type_: c_int,
+    window: c_ulong,
+    atom: Atom,
+}
+
+// What `_next_drag_event` picks up besides the events for the dragging window
+struct DragEventMatch {
+    window: c_ulong,
+    xdnd_messages: [c_ulong; 4],
+    xdnd_selection: c_ulong,
+}
+
+struct ErrorTrap {
+    display: usize,
+    first_serial: c_ulong,
+    errors: Vec<XError>,
+}
+
+// Takes the trap off the stack again, even if the trapped closure panics
+struct ErrorTrapGuard {
+    display: *mut x11::xlib::Display,
+    previous: Option<unsafe extern "C" fn(*mut x11::xlib::Display, *mut XErrorEvent) -> c_int>,
+    popped: bool,
+}
+
+impl ErrorTrapGuard {
+    fn pop(&mut self) -> Vec<XError> {
+        if self.popped {
+            return Vec::new();
+        }
+        self.popped = true;
+
+        let errors = {
+            let mut traps = ERROR_TRAPS
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            traps
+                .iter()
+                .rposition(|trap| trap.display == self.display as usize)
+                .map_or_else(Vec::new, |index| traps.remove(index).errors)
+        };
+        unsafe { XSetErrorHandler(self.previous) };
+        errors
+    }
+}
+
+impl Drop for ErrorTrapGuard {
+    fn drop(&mut self) {
+        self.pop();
+    }
+}
+
+type ErrorHandler = Box<dyn Fn(&XError) + Send>;
+type IoErrorHandler = Box<dyn Fn(&str) + Send>;
+
+static INSTALL_ERROR_HANDLERS: Once = Once::new();
+static ERROR_TRAPS: Mutex<Vec<ErrorTrap>> = Mutex::new(Vec::new());
+// Where untrapped errors go, see `Display::set_error_handler`; stderr if unset
+static ERROR_HANDLER: Mutex<Option<ErrorHandler>> = Mutex::new(None);
+static IO_ERROR_HANDLER: Mutex<Option<IoErrorHandler>> = Mutex::new(None);
+static ATOM_CACHE: Mutex<AtomCache> = Mutex::new(AtomCache {
+    atoms: BTreeMap::new(),
+    names: BTreeMap::new(),
+});
+static SELECTIONS: Mutex<Vec<OwnedSelection>> = Mutex::new(Vec::new());
+static INCR_TRANSFERS: Mutex<Vec<IncrTransfer>> = Mutex::new(Vec::new());
+static XDND_DRAGS: Mutex<Vec<XdndDrag>> = Mutex::new(Vec::new());
+// (display, window) pairs waiting for a RedrawRequested event
+static REDRAW_REQUESTS: Mutex<Vec<(usize, c_ulong)>> = Mutex::new(Vec::new());
+// Every window's back buffer, for the events that only carry a window id
+static BACK_BUFFERS: Mutex<Vec<SharedBackBuffer>> = Mutex::new(Vec::new());
+// (display, XIM) pairs, opened on first use
+static INPUT_METHODS: Mutex<Vec<(usize, usize)>> = Mutex::new(Vec::new());
+static INPUT_CONTEXTS: Mutex<Vec<InputContext>> = Mutex::new(Vec::new());
+// Events raised from Xlib callbacks, delivered by the event loop after the current event
+static PENDING_EVENTS: Mutex<Vec<(usize, c_ulong, WindowEvent)>> = Mutex::new(Vec::new());
+
+fn _trap_errors<F, R>(display: *mut x11::xlib::Display, func: F) -> (R, Vec<XError>)
+where
+    F: FnOnce() -> R,
+{
+    unsafe {
+        // Errors from earlier requests must not end up in this trap
+        XSync(display, False);
+
+        let mut guard = ErrorTrapGuard {
+            display,
+            previous: XSetErrorHandler(Some(_error_handler)),
+            popped: false,
+        };
+        ERROR_TRAPS.lock().unwrap().push(ErrorTrap {
+            display: display as usize,
+            first_serial: XNextRequest(display),
+            errors: Vec::new(),
+        });
+
+        let result = func();
+        XSync(display, False);
+
+        (result, guard.pop())
+    }
+}
+
+fn _checked<F, R>(display: *mut x11::xlib::Display, func: F) -> Result<R, XError>
+where
+    F: FnOnce() -> R,
+{
+    let (result, mut errors) = _trap_errors(display, func);
+    if errors.is_empty() {
+        Ok(result)
+    } else {
+        Err(errors.remove(0))
+    }
+}
+
+unsafe extern "C" fn _error_handler(
+    display: *mut x11::xlib::Display,
+    event: *mut XErrorEvent,
+) -> c_int {
+    let event = &*event;
+
+    let mut buffer = [0 as c_char; 256];
+    XGetErrorText(
+        display,
+        event.error_code as c_int,
+        buffer.as_mut_ptr(),
+        buffer.len() as c_int,
+    );
+
+    let error = XError {
+        error_code: event.error_code,
+        request_code: event.request_code,
+        minor_code: event.minor_code,
+        resource_id: event.resourceid,
+        serial: event.serial,
+        description: CStr::from_ptr(buffer.as_ptr())
+            .to_string_lossy()
+            .into_owned(),
+    };
+
+    if let Ok(mut traps) = ERROR_TRAPS.lock() {
+        if let Some(trap) = traps
+            .iter_mut()
+            .rev()
+            .find(|trap| trap.display == display as usize && error.serial >= trap.first_serial)
+        {
+            trap.errors.push(error);
+            return 0;
+        }
+    }
+
+    match ERROR_HANDLER.lock().as_deref() {
+        Ok(Some(handler)) => handler(&error),
+        // Reported the way Xlib's default handler does, minus the exit
+        _ => eprintln!("X Error of failed request: {}", error),
+    }
+    0
+}
+
+unsafe extern "C" fn _io_error_handler(display: *mut x11::xlib::Display) -> c_int {
+    let name = CStr::from_ptr(XDisplayString(display)).to_string_lossy();
+    match IO_ERROR_HANDLER.lock().as_deref() {
+        Ok(Some(handler)) => handler(&name),
+        _ => eprintln!("safex: lost connection to X server \"{}\"", name),
+    }
+    // Xlib exits the process as soon as this returns
+    0
+}
+
+fn _install_error_handlers() {
+    INSTALL_ERROR_HANDLERS.call_once(|| unsafe {
+        XSetErrorHandler(Some(_error_handler));
+        XSetIOErrorHandler(Some(_io_error_handler));
+    });
+}
+
+// Drops everything the registries hold for a display that is being closed, so a connection
+// that later gets the same address starts out clean
+fn _forget_display(display: *mut x11::xlib::Display) {
+    let display = display as usize;
+    ATOM_CACHE.lock().unwrap().forget(display);
+    SELECTIONS
+        .lock()
+        .unwrap()
+        .retain(|owned| owned.display != display);
+    INCR_TRANSFERS
+        .lock()
+        .unwrap()
+        .retain(|transfer| transfer.display != display);
+    XDND_DRAGS
+        .lock()
+        .unwrap()
+        .retain(|drag| drag.display != display);
+    REDRAW_REQUESTS
+        .lock()
+        .unwrap()
+        .retain(|(requester, _)| *requester != display);
+    // The server frees the pixmaps along with the connection
+    BACK_BUFFERS
+        .lock()
+        .unwrap()
+        .retain(|back_buffer| back_buffer.lock().unwrap().display != display);
+    PENDING_EVENTS
+        .lock()
+        .unwrap()
+        .retain(|(event_display, _, _)| *event_display != display);
+    #[cfg(feature = "xkb")]
+    crate::xkb::_forget_display(display);
+    #[cfg(feature = "xinput")]
+    crate::xinput::_forget_display(display);
+}
+
+fn _intern(display: *mut x11::xlib::Display, name: &str) -> Atom {
+    _intern_atom(display, name, false).unwrap_or(Atom::NONE)
+}
+
+fn _intern_atom(
+    display: *mut x11::xlib::Display,
+    name: &str,
+    only_if_exists: bool,
+) -> Option<Atom> {
+    let key = (display as usize, name.to_string());
+    if let Some(atom) = ATOM_CACHE.lock().unwrap().atoms.get(&key) {
+        return Some(*atom);
+    }
+
+    let c_name = CString::new(name).ok()?;
+    let atom = unsafe { XInternAtom(display, c_name.as_ptr(), only_if_exists as c_int) };
+    if atom == 0 {
+        return None;
+    }
+
+    ATOM_CACHE.lock().unwrap().insert(key, Atom(atom));
+    Some(Atom(atom))
+}
+
+// Lets the WM ask us to close the window instead of killing the connection
+unsafe fn _set_wm_protocols(display: *mut x11::xlib::Display, window: c_ulong) {
+    let mut protocols = [
+        _intern(display, "WM_DELETE_WINDOW").0,
+        _intern(display, "_NET_WM_PING").0,
+    ];
+    XSetWMProtocols(
+        display,
+        window,
+        protocols.as_mut_ptr(),
+        protocols.len() as c_int,
+    );
+}
+
+unsafe fn _open_input_method(display: *mut x11::xlib::Display) -> Option<XIM> {
+    let mut methods = INPUT_METHODS.lock().unwrap();
+    if let Some(&(_, im)) = methods
+        .iter()
+        .find(|(im_display, _)| *im_display == display as usize)
+    {
+        return Some(im as XIM);
+    }
+
+    let locale = libc::setlocale(libc::LC_CTYPE, null());
+    if locale.is_null() || matches!(CStr::from_ptr(locale).to_bytes(), b"C" | b"POSIX") {
+        libc::setlocale(libc::LC_CTYPE, c"".as_ptr());
+    }
+    if XSupportsLocale() == 0 {
+        return None;
+    }
+
+    // An empty modifier list reads XMODIFIERS; if the IME it names isn't running, the
+    // built-in input method still handles dead keys and compose sequences
+    let mut im = null_mut();
+    for modifiers in [c"", c"@im=none"] {
+        XSetLocaleModifiers(modifiers.as_ptr());
+        im = XOpenIM(display, null_mut(), null_mut(), null_mut());
+        if !im.is_null() {
+            break;
+        }
+    }
+    if im.is_null() {
+        return None;
+    }
+
+    methods.push((display as usize, im as usize));
+    Some(im)
+}
+
+unsafe fn _close_input_method(display: *mut x11::xlib::Display) {
+    let contexts: Vec<InputContext> = {
+        let mut contexts = INPUT_CONTEXTS.lock().unwrap();
+        let (removed, kept) = contexts
+            .drain(..)
+            .partition(|context| context.display == display as usize);
+        *contexts = kept;
+        removed
+    };
+    for context in contexts {
+        XDestroyIC(context.ic as XIC);
+    }
+
+    let mut methods = INPUT_METHODS.lock().unwrap();
+    if let Some(index) = methods
+        .iter()
+        .position(|(im_display, _)| *im_display == display as usize)
+    {
+        XCloseIM(methods.remove(index).1 as XIM);
+    }
+}
+
+unsafe fn _supported_input_styles(im: XIM) -> Vec<c_ulong> {
+    let mut styles: *mut XIMStyles = null_mut();
+    let failed = XGetIMValues(
+        im,
+        XNQueryInputStyle_0.as_ptr(),
+        &mut styles as *mut *mut XIMStyles,
+        null_mut::<c_void>(),
+    );
+    if !failed.is_null() || styles.is_null() {
+        return Vec::new();
+    }
+
+    let supported =
+        std::slice::from_raw_parts((*styles).supported_styles, (*styles).count_styles as usize)
+            .to_vec();
+    XFree(styles as *mut _);
+    supported
+}
+
+unsafe fn _create_input_context(
+    im: XIM,
+    style: PreeditStyle,
+    window: c_ulong,
+    preedit_attributes: *mut c_void,
+) -> XIC {
+    if preedit_attributes.is_null() {
+        XCreateIC(
+            im,
+            XNInputStyle_0.as_ptr(),
+            style.as_raw(),
+            XNClientWindow_0.as_ptr(),
+            window,
+            XNFocusWindow_0.as_ptr(),
+            window,
+            null_mut::<c_void>(),
+        )
+    } else {
+        XCreateIC(
+            im,
+            XNInputStyle_0.as_ptr(),
+            style.as_raw(),
+            XNClientWindow_0.as_ptr(),
+            window,
+            XNFocusWindow_0.as_ptr(),
+            window,
+            XNPreeditAttributes_0.as_ptr(),
+            preedit_attributes,
+            null_mut::<c_void>(),
+        )
+    }
+}
+
+fn _input_context(display: *mut x11::xlib::Display, window: c_ulong) -> Option<XIC> {
+    INPUT_CONTEXTS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|context| context.is(display, window))
+        .map(|context| context.ic as XIC)
+}
+
+// Applies a preedit callback to the state of the window in `client_data` and queues the event
+unsafe fn _update_preedit<F>(client_data: XPointer, update: F)
+where
+    F: FnOnce(&mut Vec<char>, &mut usize) -> WindowEvent,
+{
+    let (display, window) = *(client_data as *const (usize, c_ulong));
+    let mut contexts = INPUT_CONTEXTS.lock().unwrap();
+    let Some(context) = contexts
+        .iter_mut()
+        .find(|context| context.display == display && context.window == window)
+    else {
+        return;
+    };
+
+    let event = update(&mut context.preedit, &mut context.caret);
+    PENDING_EVENTS
+        .lock()
+        .unwrap()
+        .push((display, window, event));
+}
+
+unsafe extern "C" fn _preedit_start(_: XIM, client_data: XPointer, _: XPointer) -> c_int {
+    _update_preedit(client_data, |preedit, caret| {
+        preedit.clear();
+        *caret = 0;
+        WindowEvent::PreeditStart
+    });
+    // No limit on the preedit length
+    -1
+}
+
+unsafe extern "C" fn _preedit_done(_: XIM, client_data: XPointer, _: XPointer) {
+    _update_preedit(client_data, |preedit, caret| {
+        preedit.clear();
+        *caret = 0;
+        WindowEvent::PreeditEnd
+    });
+}
+
+unsafe extern "C" fn _preedit_draw(_: XIM, client_data: XPointer, call_data: XPointer) {
+    let draw = &*(call_data as *const XIMPreeditDrawCallbackStruct);
+
+    // A null text deletes the changed range; a text with a null string only changes feedback
+    let replacement = if draw.text.is_null() {
+        Some(Vec::new())
+    } else {
+        _xim_text(&*draw.text)
+    };
+
+    _update_preedit(client_data, |preedit, caret| {
+        if let Some(replacement) = replacement {
+            let first = (draw.chg_first.max(0) as usize).min(preedit.len());
+            let end = (first + draw.chg_length.max(0) as usize).min(preedit.len());
+            preedit.splice(first..end, replacement);
+        }
+        *caret = (draw.caret.max(0) as usize).min(preedit.len());
+
+        WindowEvent::Preedit {
+            text: preedit.iter().collect(),
+            caret: *caret,
+        }
+    });
+}
+
+unsafe extern "C" fn _preedit_caret(_: XIM, client_data: XPointer, call_data: XPointer) {
+    let caret_data = call_data as *const XIMPreeditCaretCallbackStruct;
+    // Read the direction as an integer; it comes from C and may hold values the Rust enum lacks
+    let direction = *(addr_of!((*caret_data).direction) as *const c_int);
+    if direction != XIMCaretDirection::XIMAbsolutePosition as c_int {
+        return;
+    }
+    let position = (*caret_data).position;
+
+    _update_preedit(client_data, |preedit, caret| {
+        *caret = (position.max(0) as usize).min(preedit.len());
+        WindowEvent::Preedit {
+            text: preedit.iter().collect(),
+            caret: *caret,
+        }
+    });
+}
+
+// XIMText strings are in the locale's multibyte encoding, or wchar_t when flagged
+unsafe fn _xim_text(text: &XIMText) -> Option<Vec<char>> {
+    let string = text.string.multi_byte;
+    if string.is_null() {
+        return None;
+    }
+
+    Some(if text.encoding_is_wchar != 0 {
+        std::slice::from_raw_parts(string as *const libc::wchar_t, text.length as usize)
+            .iter()
+            .filter_map(|&c| char::from_u32(c as u32))
+            .collect()
+    } else {
+        CStr::from_ptr(string).to_string_lossy().chars().collect()
+    })
+}
+
+fn _take_pending_events(display: *mut x11::xlib::Display) -> Vec<(c_ulong, WindowEvent)> {
+    let mut pending = PENDING_EVENTS.lock().unwrap();
+    let (ours, others): (Vec<_>, Vec<_>) = pending
+        .drain(..)
+        .partition(|(event_display, _, _)| *event_display == display as usize);
+    *pending = others;
+    ours.into_iter()
+        .map(|(_, window, event)| (window, event))
+        .collect()
+}
+
+// Latin-1 keysyms equal their code points; 0x01000000 plus a code point covers the rest of
+// Unicode. Other legacy keysyms need the xkb table.
+#[cfg(not(feature = "xkb"))]
+fn _keysym_to_char(keysym: KeySym) -> Option<char> {
+    match keysym {
+        0x20..=0x7e | 0xa0..=0xff => char::from_u32(keysym as u32),
+        0x0100_0100..=0x0110_ffff => char::from_u32((keysym - 0x0100_0000) as u32),
+        _ => None,
+    }
+}
+
+// Control characters such as KP_Enter's are left to the named keys
+#[cfg(feature = "xkb")]
+fn _keysym_to_char(keysym: KeySym) -> Option<char> {
+    crate::xkb::keysym_to_char(keysym).filter(|c| !c.is_control())
+}
+
+#[allow(non_upper_case_globals)]
+unsafe fn _lookup_utf8(ic: XIC, key: &mut XKeyEvent) -> (KeySym, Option<String>) {
+    let mut buffer = vec![0u8; 64];
+    loop {
+        let (mut keysym, mut status) = (0, 0);
+        let len = Xutf8LookupString(
+            ic,
+            key,
+            buffer.as_mut_ptr() as *mut c_char,
+            buffer.len() as c_int,
+            &mut keysym,
+            &mut status,
+        );
+        if status == XBufferOverflow {
+            buffer.resize(len as usize, 0);
+            continue;
+        }
+
+        let text = matches!(status, XLookupChars | XLookupBoth)
+            .then(|| String::from_utf8_lossy(&buffer[..len.max(0) as usize]).into_owned())
+            .filter(|text| !text.is_empty());
+        let keysym = if matches!(status, XLookupKeySym | XLookupBoth) {
+            keysym
+        } else if key.keycode != 0 {
+            XLookupKeysym(key, 0)
+        } else {
+            // Text committed by the input method rather than typed
+            0
+        };
+        return (keysym, text);
+    }
+}
+
+unsafe fn _lookup_latin1(key: &mut XKeyEvent) -> (KeySym, Option<String>) {
+    let mut keysym = 0;
+    let mut buffer = [0 as c_char; 32];
+    let len = XLookupString(
+        key,
+        buffer.as_mut_ptr(),
+        buffer.len() as c_int,
+        &mut keysym,
+        null_mut(),
+    );
+
+    // XLookupString produces Latin-1, which maps directly onto the first 256 code points
+    let text: String = buffer[..len.max(0) as usize]
+        .iter()
+        .map(|&c| c as u8 as char)
+        .collect();
+    (keysym, (!text.is_empty()).then_some(text))
+}
+
+#[cfg_attr(not(feature = "dbe"), allow(unused_variables))]
+unsafe fn _create_back_buffer(
+    display: *mut x11::xlib::Display,
+    window: c_ulong,
+    mode: BufferMode,
+    background: c_ulong,
+) -> SharedBackBuffer {
+    // The pixmap has to follow the window size, whatever else the window listens to
+    XSelectInput(
+        display,
+        window,
+        (_event_mask(display, window) | StructureNotifyMask) as c_long,
+    );
+
+    let geometry = _get_geometry(display, window);
+    let mut back_buffer = BackBuffer {
+        display: display as usize,
+        window,
+        drawable: 0,
+        width: geometry.width,
+        height: geometry.height,
+        depth: geometry.depth,
+        background,
+        preserve: true,
+        #[cfg(feature = "dbe")]
+        swap_action: None,
+    };
+
+    // Not every visual supports DBE even when the server does
+    #[cfg(feature = "dbe")]
+    if mode == BufferMode::Dbe && dbe::_query_version(display).is_some() {
+        let swap_action = SwapAction::default();
+        if let Ok(drawable) = _checked(display, || {
+            dbe::_allocate_back_buffer(display, window, swap_action)
+        }) {
+            back_buffer.drawable = drawable;
+            back_buffer.swap_action = Some(swap_action);
+        }
+    }
+    if !back_buffer.is_dbe() {
+        back_buffer.drawable = XCreatePixmap(
+            display,
+            window,
+            geometry.width,
+            geometry.height,
+            geometry.depth,
+        );
+        _fill_drawable(
+            display,
+            back_buffer.drawable,
+            geometry.width,
+            geometry.height,
+            background,
+        );
+    }
+
+    let back_buffer = std::sync::Arc::new(Mutex::new(back_buffer));
+    BACK_BUFFERS.lock().unwrap().push(back_buffer.clone());
+    back_buffer
+}
+
+// Swaps in a pixmap of the new size, carrying the old content over if asked to. DBE back
+// buffers are resized by the server along with the window.
+fn _resize_back_buffer(display: *mut x11::xlib::Display, window: c_ulong, width: u32, height: u32) {
+    let Some(back_buffer) = BACK_BUFFERS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|back_buffer| back_buffer.lock().unwrap().is(display, window))
+        .cloned()
+    else {
+        return;
+    };
+    let mut back_buffer = back_buffer.lock().unwrap();
+    if back_buffer.is_dbe() {
+        return;
+    }
+    if (back_buffer.width, back_buffer.height) == (width, height) || width == 0 || height == 0 {
+        return;
+    }
+
+    unsafe {
+        let pixmap = XCreatePixmap(display, window, width, height, back_buffer.depth);
+        _fill_drawable(display, pixmap, width, height, back_buffer.background);
+
+        if back_buffer.preserve {
+            let gc = XCreateGC(display, pixmap, 0, null_mut());
+            XCopyArea(
+                display,
+                back_buffer.drawable,
+                pixmap,
+                gc,
+                0,
+                0,
+                back_buffer.width.min(width),
+                back_buffer.height.min(height),
+                0,
+                0,
+            );
+            XFreeGC(display, gc);
+        }
+        XFreePixmap(display, back_buffer.drawable);
+
+        back_buffer.drawable = pixmap;
+        back_buffer.width = width;
+        back_buffer.height = height;
+    }
+}
+
+unsafe fn _free_back_buffer(display: *mut x11::xlib::Display, window: c_ulong) {
+    BACK_BUFFERS.lock().unwrap().retain(|back_buffer| {
+        let back_buffer = back_buffer.lock().unwrap();
+        if !back_buffer.is(display, window) {
+            return true;
+        }
+
+        #[cfg(feature = "dbe")]
+        if back_buffer.is_dbe() {
+            dbe::_deallocate_back_buffer(display, back_buffer.drawable);
+            return false;
+        }
+        XFreePixmap(display, back_buffer.drawable);
+        false
+    });
+}
+
+// New pixmaps have undefined content
+unsafe fn _fill_drawable(
+    display: *mut x11::xlib::Display,
+    drawable: c_ulong,
+    width: u32,
+    height: u32,
+    pixel: c_ulong,
+) {
+    let gc = XCreateGC(display, drawable, 0, null_mut());
+    XSetForeground(display, gc, pixel);
+    XFillRectangle(display, drawable, gc, 0, 0, width, height);
+    XFreeGC(display, gc);
+}
+
+unsafe fn _get_screen_number(display: *mut x11::xlib::Display, window: c_ulong) -> c_int {
+    let mut attributes: XWindowAttributes = std::mem::zeroed();
+    XGetWindowAttributes(display, window, &mut attributes);
+    XScreenNumberOfScreen(attributes.screen)
+}
+
+// Top-level windows are the children of any screen's root, not only the default one
+unsafe fn _is_root_window(display: *mut x11::xlib::Display, window: c_ulong) -> bool {
+    (0..XScreenCount(display)).any(|screen| XRootWindow(display, screen) == window)
+}
+
+// Identifies the process behind a top-level window, as ICCCM and EWMH ask clients to
+unsafe fn _set_client_properties(display: *mut x11::xlib::Display, window: c_ulong) {
+    let mut hostname = [0 as c_char; 256];
+    if libc::gethostname(hostname.as_mut_ptr(), hostname.len() - 1) != 0 {
+        return;
+    }
+    let hostname = CStr::from_ptr(hostname.as_ptr()).to_bytes();
+
+    // _NET_WM_PID is only meaningful next to the machine it belongs to
+    XChangeProperty(
+        display,
+        window,
+        Atom::WM_CLIENT_MACHINE.0,
+        Atom::STRING.0,
+        8,
+        PropModeReplace,
+        hostname.as_ptr(),
+        hostname.len() as c_int,
+    );
+    let pid = std::process::id() as c_long;
+    XChangeProperty(
+        display,
+        window,
+        _intern(display, "_NET_WM_PID").0,
+        Atom::CARDINAL.0,
+        32,
+        PropModeReplace,
+        addr_of!(pid) as *const u8,
+        1,
+    );
+}
+
+fn _run_event_loop<F>(display: *mut x11::xlib::Display, mut func: F)
+where
+    F: FnMut(Option<WindowId>, WindowEvent, &mut ControlFlow),
+{
+    let mut control_flow = ControlFlow::Wait;
+    loop {
+        while unsafe { XPending(display) } > 0 {
+            let mut event = unsafe {
+                let mut event = MaybeUninit::uninit();
+                XNextEvent(display, event.as_mut_ptr());
+                event.assume_init()
+            };
+
+            if let Some(event) = _translate_extension_event(display, &mut event) {
+                func(None, event, &mut control_flow);
+                if control_flow == ControlFlow::Exit {
+                    return;
+                }
+                continue;
+            }
+
+            // Input methods see events first and swallow the ones they consume
+            if unsafe { XFilterEvent(&mut event, 0) } == 0 {
+                let window = WindowId(unsafe { event.any.window });
+                if let Some(event) = _translate_event(&mut event) {
+                    func(Some(window), event, &mut control_flow);
+                    if control_flow == ControlFlow::Exit {
+                        return;
+                    }
+                }
+            }
+
+            for (window, event) in _take_pending_events(display) {
+                func(Some(WindowId(window)), event, &mut control_flow);
+                if control_flow == ControlFlow::Exit {
+                    return;
+                }
+            }
+        }
+
+        let requests: Vec<c_ulong> = {
+            let mut requests = REDRAW_REQUESTS.lock().unwrap();
+            let (ours, others) = requests
+                .drain(..)
+                .partition(|(requester, _)| *requester == display as usize);
+            *requests = others;
+            ours.into_iter().map(|(_, window)| window).collect()
+        };
+        for window in requests {
+            func(
+                Some(WindowId(window)),
+                WindowEvent::RedrawRequested,
+                &mut control_flow,
+            );
+        }
+        func(None, WindowEvent::AboutToWait, &mut control_flow);
+
+        match control_flow {
+            ControlFlow::Exit => return,
+            ControlFlow::Poll => {}
+            ControlFlow::Wait => _wait_for_events(display, None),
+            ControlFlow::WaitUntil(deadline) => _wait_for_events(display, Some(deadline)),
+        }
+    }
+}
+
+// Blocks until the connection becomes readable or `deadline` passes
+fn _wait_for_events(display: *mut x11::xlib::Display, deadline: Option<Instant>) {
+    // Anything Xlib already read off the socket would not wake poll(2) up
+    if unsafe { XPending(display) } > 0 {
+        return;
+    }
+
+    _poll_connection(display, deadline);
+}
+
+fn _poll_connection(display: *mut x11::xlib::Display, deadline: Option<Instant>) {
+    unsafe {
+        let timeout = match deadline {
+            None => -1,
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                // Round up so we don't wake up just before the deadline and spin
+                remaining.as_micros().div_ceil(1000).min(c_int::MAX as u128) as c_int
+            }
+        };
+
+        let mut fd = libc::pollfd {
+            fd: XConnectionNumber(display),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        libc::poll(&mut fd, 1, timeout);
+    }
+}
+
+// Pulls the first event matching `target` out of the queue, leaving the others in place
+#[allow(non_upper_case_globals)]
+fn _wait_for_event(
+    display: *mut x11::xlib::Display,
+    target: EventMatch,
+    deadline: Instant,
+) -> Option<XEvent> {
+    unsafe extern "C" fn predicate(
+        _display: *mut x11::xlib::Display,
+        event: *mut XEvent,
+        arg: *mut c_char,
+    ) -> c_int {
+        let event = &*event;
+        let target = &*(arg as *const EventMatch);
+        let matches = event.type_ == target.type_
+            && match event.type_ {
+                SelectionNotify => {
+                    event.selection.requestor == target.window
+                        && event.selection.selection == target.atom.0
+                }
+                PropertyNotify => {
+                    event.property.window == target.window
+                        && event.property.atom == target.atom.0
+                        && event.property.state == PropertyNewValue
+                }
+                _ => event.any.window == target.window,
+            };
+        matches as c_int
+    }
+
+    unsafe extern "C" fn serves_selection(
+        display: *mut x11::xlib::Display,
+        event: *mut XEvent,
+        _arg: *mut c_char,
+    ) -> c_int {
+        let event = &*event;
+        let serves = match event.type_ {
+            SelectionRequest => true,
+            PropertyNotify => {
+                event.property.state == PropertyDelete
+                    && INCR_TRANSFERS.lock().unwrap().iter().any(|transfer| {
+                        transfer.display == display as usize
+                            && transfer.requestor == event.property.window
+                            && transfer.property.0 == event.property.atom
+                    })
+            }
+            _ => false,
+        };
+        serves as c_int
+    }
+
+    unsafe {
+        let mut event = MaybeUninit::uninit();
+        loop {
+            // Keep serving our own selections, or converting one of them would wait for
+            // ourselves until the deadline
+            while XCheckIfEvent(
+                display,
+                event.as_mut_ptr(),
+                Some(serves_selection),
+                null_mut(),
+            ) != 0
+            {
+                let event = event.assume_init_ref();
+                if event.type_ == SelectionRequest {
+                    _serve_selection_request(&event.selection_request);
+                } else {
+                    _continue_incr_transfer(&event.property);
+                }
+            }
+
+            if XCheckIfEvent(
+                display,
+                event.as_mut_ptr(),
+                Some(predicate),
+                &target as *const EventMatch as *mut c_char,
+            ) != 0
+            {
+                return Some(event.assume_init());
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+
+            // XCheckIfEvent already read everything available, so only new data can help
+            _poll_connection(display, Some(deadline));
+            // QueuedAfterReading
+            XEventsQueued(display, 1);
+        }
+    }
+}
+
+fn _serve_selection_request(request: &XSelectionRequestEvent) {
+    let display = request.display;
+    let selections = SELECTIONS.lock().unwrap();
+    let owned = selections.iter().find(|owned| {
+        owned.display == display as usize
+            && owned.selection.0 == request.selection
+            && owned.owner == request.owner
+            // ICCCM: refuse requests made before we took the selection
+            && (request.time == CurrentTime || request.time >= owned.time)
+    });
+
+    // Obsolete clients leave the property empty and expect us to use the target
+    let property = if request.property == 0 {
+        request.target
+    } else {
+        request.property
+    };
+    let targets = _intern(display, "TARGETS");
+
+    let served = unsafe {
+        match owned {
+            None => false,
+            Some(owned) if request.target == targets.0 => {
+                let mut atoms: Vec<c_long> = vec![targets.0 as c_long];
+                atoms.extend(
+                    owned
+                        .content
+                        .targets
+                        .iter()
+                        .map(|(target, _, _)| target.0 as c_long),
+                );
+                XChangeProperty(
+                    display,
+                    request.requestor,
+                    property,
+                    XA_ATOM,
+                    32,
+                    PropModeReplace,
+                    atoms.as_ptr() as *const u8,
+                    atoms.len() as c_int,
+                );
+                true
+            }
+            Some(owned) => match owned
+                .content
+                .targets
+                .iter()
+                .find(|(target, _, _)| target.0 == request.target)
+            {
+                None => false,
+                Some((_, type_, data)) => {
+                    let max_size = (XMaxRequestSize(display) as usize * 4).saturating_sub(100);
+                    if data.len() > max_size {
+                        let len = data.len() as c_long;
+                        let mut transfers = INCR_TRANSFERS.lock().unwrap();
+                        // A transfer already running to the requestor has the original mask
+                        let event_mask = transfers
+                            .iter()
+                            .find(|transfer| {
+                                transfer.display == display as usize
+                                    && transfer.requestor == request.requestor
+                            })
+                            .map_or_else(
+                                || _event_mask(display, request.requestor),
+                                |transfer| transfer.event_mask,
+                            );
+                        XSelectInput(
+                            display,
+                            request.requestor,
+                            (event_mask | PropertyChangeMask) as c_long,
+                        );
+                        XChangeProperty(
+                            display,
+                            request.requestor,
+                            property,
+                            _intern(display, "INCR").0,
+                            32,
+                            PropModeReplace,
+                            &len as *const c_long as *const u8,
+                            1,
+                        );
+                        transfers.push(IncrTransfer {
+                            display: display as usize,
+                            requestor: request.requestor,
+                            property: Atom(property),
+                            type_: *type_,
+                            data: data.clone(),
+                            offset: 0,
+                            event_mask,
+                        });
+                    } else {
+                        XChangeProperty(
+                            display,
+                            request.requestor,
+                            property,
+                            type_.0,
+                            8,
+                            PropModeReplace,
+                            data.as_ptr(),
+                            data.len() as c_int,
+                        );
+                    }
+                    true
+                }
+            },
+        }
+    };
+
+    let mut notify: XEvent = unsafe { std::mem::zeroed() };
+    notify.selection = XSelectionEvent {
+        type_: SelectionNotify,
+        serial: 0,
+        send_event: True,
+        display,
+        requestor: request.requestor,
+        selection: request.selection,
+        target: request.target,
+        property: if served { property } else { 0 },
+        time: request.time,
+    };
+    unsafe {
+        XSendEvent(
+            display,
+            request.requestor,
+            False,
+            NoEventMask as c_long,
+            &mut notify,
+        );
+    }
+}
+
+// Sends the next INCR chunk once the requestor deleted the previous one.
+// Returns false if the event does not belong to a transfer.
+fn _continue_incr_transfer(event: &XPropertyEvent) -> bool {
+    if event.state != PropertyDelete {
+        return false;
+    }
+
+    let display = event.display;
+    let mut transfers = INCR_TRANSFERS.lock().unwrap();
+    let index = match transfers.iter().position(|transfer| {
+        transfer.display == display as usize
+            && transfer.requestor == event.window
+            && transfer.property.0 == event.atom
+    }) {
+        Some(index) => index,
+        None => return false,
+    };
+
+    let transfer = &mut transfers[index];
+    let max_size = unsafe { (XMaxRequestSize(display) as usize * 4).saturating_sub(100) };
+    let end = (transfer.offset + max_size).min(transfer.data.len());
+    let chunk = &transfer.data[transfer.offset..end];
+
+    unsafe {
+        XChangeProperty(
+            display,
+            transfer.requestor,
+            transfer.property.0,
+            transfer.type_.0,
+            8,
+            PropModeReplace,
+            chunk.as_ptr(),
+            chunk.len() as c_int,
+        );
+    }
+
+    // The zero-length chunk that ends the transfer has just been written
+    if chunk.is_empty() {
+        let transfer = transfers.remove(index);
+        // Hand the requestor its own mask back once no other transfer needs the notifications
+        if !transfers
+            .iter()
+            .any(|other| other.display == transfer.display && other.requestor == transfer.requestor)
+        {
+            unsafe { XSelectInput(display, transfer.requestor, transfer.event_mask as c_long) };
+        }
+    } else {
+        transfer.offset = end;
+    }
+    true
+}
+
+// The events this client selected on `window`
+fn _event_mask(display: *mut x11::xlib::Display, window: c_ulong) -> Mask {
+    unsafe {
+        let mut attributes: XWindowAttributes = std::mem::zeroed();
+        XGetWindowAttributes(display, window, &mut attributes);
+        attributes.your_event_mask as Mask
+    }
+}
+
+// Views a window we don't own through the WindowRef API
+unsafe fn _borrow_window<'d>(display: *mut x11::xlib::Display, window: c_ulong) -> WindowRef<'d> {
+    WindowRef::new(display, window)
+}
+
+fn _send_client_message(
+    display: *mut x11::xlib::Display,
+    destination: c_ulong,
+    window: c_ulong,
+    message_type: Atom,
+    data: [c_long; 5],
+    mask: Mask,
+) {
+    unsafe {
+        let mut event: XEvent = std::mem::zeroed();
+        event.client_message.type_ = ClientMessage;
+        event.client_message.display = display;
+        event.client_message.window = window;
+        event.client_message.message_type = message_type.0;
+        event.client_message.format = 32;
+        for (i, value) in data.into_iter().enumerate() {
+            event.client_message.data.set_long(i, value);
+        }
+        XSendEvent(display, destination, False, mask as c_long, &mut event);
+        XFlush(display);
+    }
+}
+
+fn _send_xdnd_message(
+    display: *mut x11::xlib::Display,
+    window: c_ulong,
+    message_type: &str,
+    data: [c_long; 5],
+) {
+    let message_type = _intern(display, message_type);
+    _send_client_message(display, window, window, message_type, data, NoEventMask);
+}
+
+// Searches the top-level window `toplevel` for the shallowest window that speaks XDND,
+// which is the client window when `toplevel` is a window manager frame
+fn _find_xdnd_target(display: *mut x11::xlib::Display, toplevel: c_ulong) -> Option<XdndTarget> {
+    let xdnd_aware = _intern(display, "XdndAware");
+    let xdnd_proxy = _intern(display, "XdndProxy");
+
+    // Windows may be destroyed while we look; those simply aren't targets
+    let (target, _) = _trap_errors(display, || unsafe {
+        let mut level = vec![toplevel];
+        while !level.is_empty() {
+            let mut next = Vec::new();
+            for &window in &level {
+                // A proxy has to name itself too, otherwise it was left behind by a dead client
+                let proxy = _first_property_item(display, window, xdnd_proxy, Atom::WINDOW)
+                    .filter(|&proxy| {
+                        _first_property_item(display, proxy, xdnd_proxy, Atom::WINDOW)
+                            == Some(proxy)
+                    })
+                    .unwrap_or(window);
+                if let Some(version) = _first_property_item(display, proxy, xdnd_aware, Atom::ATOM)
+                {
+                    return Some(XdndTarget {
+                        window,
+                        proxy,
+                        version: (version as c_long).min(XDND_VERSION),
+                    });
+                }
+
+                let mut root = 0;
+                let mut parent = 0;
+                let mut children = null_mut();
+                let mut count = 0;
+                if XQueryTree(
+                    display,
+                    window,
+                    &mut root,
+                    &mut parent,
+                    &mut children,
+                    &mut count,
+                ) != 0
+                    && !children.is_null()
+                {
+                    next.extend_from_slice(std::slice::from_raw_parts(children, count as usize));
+                    XFree(children as *mut _);
+                }
+            }
+            level = next;
+        }
+        None
+    });
+    target
+}
+
+// The first item of a 32-bit property, the shape XdndAware and XdndProxy have. Errors are
+// left to the caller's trap.
+unsafe fn _first_property_item(
+    display: *mut x11::xlib::Display,
+    window: c_ulong,
+    property: Atom,
+    type_: Atom,
+) -> Option<c_ulong> {
+    let mut actual_type = 0;
+    let mut format = 0;
+    let mut nitems = 0;
+    let mut bytes_after = 0;
+    let mut data = null_mut();
+    let status = XGetWindowProperty(
+        display,
+        window,
+        property.0,
+        0,
+        1,
+        False,
+        type_.0,
+        &mut actual_type,
+        &mut format,
+        &mut nitems,
+        &mut bytes_after,
+        &mut data,
+    );
+    if status != Success as c_int || data.is_null() {
+        return None;
+    }
+
+    let item =
+        (actual_type == type_.0 && format == 32 && nitems > 0).then(|| *(data as *const c_ulong));
+    XFree(data as *mut _);
+    item
+}
+
+// Blocks until an event relevant to a drag started from `window` arrives.
+// XDND messages for our own windows are handled on the way: the event loop that would
+// otherwise answer them is the one waiting for the drag.
+#[allow(non_upper_case_globals)]
+unsafe fn _next_drag_event(
+    display: *mut x11::xlib::Display,
+    window: c_ulong,
+    deadline: Option<Instant>,
+) -> XEvent {
+    unsafe extern "C" fn predicate(
+        _display: *mut x11::xlib::Display,
+        event: *mut XEvent,
+        arg: *mut c_char,
+    ) -> c_int {
+        let event = &*event;
+        let target = &*(arg as *const DragEventMatch);
+        let matches = match event.type_ {
+            MotionNotify | ButtonRelease | KeyPress => event.any.window == target.window,
+            ClientMessage => {
+                event.client_message.window == target.window
+                    || target
+                        .xdnd_messages
+                        .contains(&event.client_message.message_type)
+            }
+            SelectionRequest => event.selection_request.owner == target.window,
+            SelectionNotify => event.selection.selection == target.xdnd_selection,
+            _ => false,
+        };
+        matches as c_int
+    }
+
+    let target = DragEventMatch {
+        window,
+        xdnd_messages: ["XdndEnter", "XdndPosition", "XdndLeave", "XdndDrop"]
+            .map(|name| _intern(display, name).0),
+        xdnd_selection: _intern(display, "XdndSelection").0,
+    };
+
+    let mut event = MaybeUninit::<XEvent>::zeroed();
+    loop {
+        if XCheckIfEvent(
+            display,
+            event.as_mut_ptr(),
+            Some(predicate),
+            &target as *const DragEventMatch as *mut c_char,
+        ) != 0
+        {
+            let mut event = event.assume_init();
+            let receiver = match event.type_ {
+                ClientMessage
+                    if target
+                        .xdnd_messages
+                        .contains(&event.client_message.message_type) =>
+                {
+                    event.client_message.window
+                }
+                SelectionNotify => event.selection.requestor,
+                _ => return event,
+            };
+            if let Some(translated) = _translate_event(&mut event) {
+                PENDING_EVENTS
+                    .lock()
+                    .unwrap()
+                    .push((display as usize, receiver, translated));
+            }
+            continue;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return std::mem::zeroed();
+        }
+
+        _poll_connection(display, deadline);
+        // QueuedAfterReading
+        XEventsQueued(display, 1);
+    }
+}
+
+// Handles the target side of XDND. Returns `None` if `message` is not an XDND message,
+// otherwise the event (if any) it translates to.
+fn _handle_xdnd_message(message: &XClientMessageEvent) -> Option<Option<WindowEvent>> {
+    let display = message.display;
+    let message_type = Atom(message.message_type);
+    let source = message.data.get_long(0) as c_ulong;
+    let key = |drag: &XdndDrag| drag.display == display as usize && drag.target == message.window;
+
+    if message_type == _intern(display, "XdndEnter") {
+        let more_types = message.data.get_long(1) & 1 != 0;
+        let types = if more_types {
+            let view = unsafe { _borrow_window(display, source) };
+            view.get_atom_property(_intern(display, "XdndTypeList"))
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+        } else {
+            (2..5)
+                .map(|i| Atom(message.data.get_long(i) as c_ulong))
+                .filter(|atom| *atom != Atom::NONE)
+                .collect()
+        };
+
+        let mut drags = XDND_DRAGS.lock().unwrap();
+        drags.retain(|drag| !key(drag));
+        drags.push(XdndDrag {
+            display: display as usize,
+            target: message.window,
+            source,
+            types,
+            paths: None,
+            requested: false,
+            dropped: false,
+        });
+        return Some(None);
+    }
+
+    if message_type == _intern(display, "XdndPosition") {
+        let uri_list = _intern(display, "text/uri-list");
+        let mut drags = XDND_DRAGS.lock().unwrap();
+        let drag = drags.iter_mut().find(|drag| key(drag))?;
+
+        if !drag.requested && drag.types.contains(&uri_list) {
+            drag.requested = true;
+            _request_dropped_paths(display, message.window, message.data.get_long(3) as c_ulong);
+        }
+        // While the paths are on their way the status goes out with them
+        if !drag.requested || drag.paths.is_some() {
+            _send_xdnd_status(display, drag);
+        }
+        return Some(None);
+    }
+
+    if message_type == _intern(display, "XdndLeave") {
+        let mut drags = XDND_DRAGS.lock().unwrap();
+        let index = drags.iter().position(key)?;
+        let drag = drags.remove(index);
+        return Some(match drag.paths {
+            Some(paths) if !paths.is_empty() => Some(WindowEvent::FileHoverCancelled),
+            _ => None,
+        });
+    }
+
+    if message_type == _intern(display, "XdndDrop") {
+        let uri_list = _intern(display, "text/uri-list");
+        let mut drags = XDND_DRAGS.lock().unwrap();
+        let index = drags.iter().position(key)?;
+        let drag = &mut drags[index];
+
+        if !drag.types.contains(&uri_list) {
+            let drag = drags.remove(index);
+            _send_xdnd_finished(display, &drag, false);
+            return Some(None);
+        }
+
+        // Fetch the paths again with the drop's own timestamp, unless a request is still
+        // outstanding; the answer finishes the drop
+        if drag.paths.is_some() || !drag.requested {
+            drag.paths = None;
+            drag.requested = true;
+            _request_dropped_paths(display, message.window, message.data.get_long(2) as c_ulong);
+        }
+        drag.dropped = true;
+        return Some(None);
+    }
+
+    None
+}
+
+// Asks the source of a drag over `target` for the dragged files. The answer is a
+// SelectionNotify, which `_handle_xdnd_paths` picks up.
+fn _request_dropped_paths(display: *mut x11::xlib::Display, target: c_ulong, time: c_ulong) {
+    unsafe {
+        XConvertSelection(
+            display,
+            _intern(display, "XdndSelection").0,
+            _intern(display, "text/uri-list").0,
+            _intern(display, "SAFEX_XDND").0,
+            target,
+            time,
+        );
+    }
+}
+
+// Takes the paths from the source's answer to `_request_dropped_paths`. Returns `None` if
+// `notify` is not such an answer, otherwise the event (if any) it translates to.
+fn _handle_xdnd_paths(notify: &XSelectionEvent) -> Option<Option<WindowEvent>> {
+    let display = notify.display;
+    if Atom(notify.selection) != _intern(display, "XdndSelection")
+        || Atom(notify.target) != _intern(display, "text/uri-list")
+    {
+        return None;
+    }
+
+    // Read before taking the lock, a large list comes as an INCR transfer
+    let paths = match notify.property {
+        0 => Vec::new(),
+        property => {
+            let view = unsafe { _borrow_window(display, notify.requestor) };
+            let deadline = Instant::now() + Duration::from_secs(1);
+            match view.read_selection_reply(Atom(property), deadline) {
+                Ok(Some(Property {
+                    data: PropertyData::Format8(data),
+                    ..
+                })) => _parse_uri_list(&String::from_utf8_lossy(&data)),
+                _ => Vec::new(),
+            }
+        }
+    };
+
+    let mut drags = XDND_DRAGS.lock().unwrap();
+    // The drag may have left in the meantime
+    let Some(index) = drags.iter().position(|drag| {
+        drag.display == display as usize
+            && drag.target == notify.requestor
+            && drag.requested
+            && drag.paths.is_none()
+    }) else {
+        return Some(None);
+    };
+
+    if drags[index].dropped {
+        let drag = drags.remove(index);
+        _send_xdnd_finished(display, &drag, !paths.is_empty());
+        return Some((!paths.is_empty()).then_some(WindowEvent::FileDropped(paths)));
+    }
+
+    let drag = &mut drags[index];
+    drag.paths = Some(paths.clone());
+    _send_xdnd_status(display, drag);
+    Some((!paths.is_empty()).then_some(WindowEvent::FileHovered(paths)))
+}
+
+fn _send_xdnd_status(display: *mut x11::xlib::Display, drag: &XdndDrag) {
+    let accepted = drag.paths.as_ref().is_some_and(|paths| !paths.is_empty());
+    let action = if accepted {
+        _intern(display, "XdndActionCopy").0 as c_long
+    } else {
+        0
+    };
+    _send_xdnd_message(
+        display,
+        drag.source,
+        "XdndStatus",
+        [
+            drag.target as c_long,
+            (accepted as c_long) | 2,
+            0,
+            0,
+            action,
+        ],
+    );
+}
+
+fn _send_xdnd_finished(display: *mut x11::xlib::Display, drag: &XdndDrag, accepted: bool) {
+    let action = if accepted {
+        _intern(display, "XdndActionCopy").0 as c_long
+    } else {
+        0
+    };
+    _send_xdnd_message(
+        display,
+        drag.source,
+        "XdndFinished",
+        [drag.target as c_long, accepted as c_long, action, 0, 0],
+    );
+}
+
+// Parses a text/uri-list (RFC 2483), keeping only local file:// URIs
+fn _parse_uri_list(list: &str) -> Vec<PathBuf> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|uri| uri.strip_prefix("file://"))
+        // "file:///path" has no host; a named one has to be this machine
+        .filter_map(|rest| {
+            let slash = rest.find('/')?;
+            _is_local_host(&rest[..slash]).then(|| &rest[slash..])
+        })
+        .map(|path| {
+            let bytes = path.as_bytes();
+            let mut decoded = Vec::with_capacity(bytes.len());
+            let mut i = 0;
+            while i < bytes.len() {
+                let escape = bytes
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match (bytes[i], escape) {
+                    (b'%', Some(byte)) => {
+                        decoded.push(byte);
+                        i += 3;
+                    }
+                    (byte, _) => {
+                        decoded.push(byte);
+                        i += 1;
+                    }
+                }
+            }
+            PathBuf::from(std::ffi::OsString::from(
+                <std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(&decoded),
+            ))
+        })
+        .collect()
+}
+
+fn _is_local_host(host: &str) -> bool {
+    if host.is_empty() || host.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+
+    let mut name = [0 as c_char; 256];
+    unsafe {
+        // One byte short, so a truncated name is still terminated
+        libc::gethostname(name.as_mut_ptr(), name.len() - 1) == 0
+            && CStr::from_ptr(name.as_ptr())
+                .to_bytes()
+                .eq_ignore_ascii_case(host.as_bytes())
+    }
+}
+
+fn _percent_encode(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str()
+        .as_bytes()
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg_attr(not(any(feature = "xkb", feature = "xinput")), allow(unused_variables))]
+fn _translate_extension_event(
+    display: *mut x11::xlib::Display,
+    event: &mut XEvent,
+) -> Option<WindowEvent> {
+    #[cfg(feature = "xkb")]
+    if let Some(event) = crate::xkb::_translate_event(display, event) {
+        return Some(event);
+    }
+    #[cfg(feature = "xinput")]
+    if let Some(event) = crate::xinput::_translate_event(display, event) {
+        return Some(event);
+    }
+    None
+}
+
+#[allow(non_upper_case_globals)]
+fn _translate_event(event: &mut XEvent) -> Option<WindowEvent> {
+    unsafe {
+        let event = match event.type_ {
+            Expose => WindowEvent::Expose,
+            KeyPress | KeyRelease => {
+                // Input contexts only look up key presses
+                let ic = match event.type_ {
+                    KeyPress => _input_context(event.key.display, event.key.window),
+                    _ => None,
+                };
+                let (keysym, text) = match ic {
+                    Some(ic) => _lookup_utf8(ic, &mut event.key),
+                    None => _lookup_latin1(&mut event.key),
+                };
+
+                let key = KeyEvent {
+                    keycode: event.key.keycode,
+                    keysym,
+                    key: Key::from_keysym(keysym),
+                    state: event.key.state,
+                    modifiers: Modifiers::from_state(event.key.state),
+                    text: text.filter(|_| event.type_ == KeyPress),
+                };
+
+                if event.type_ == KeyPress {
+                    WindowEvent::KeyPress(key)
+                } else {
+                    WindowEvent::KeyRelease(key)
+                }
+            }
+            ButtonPress | ButtonRelease => {
+                let button = ButtonEvent {
+                    button: event.button.button,
+                    x: event.button.x,
+                    y: event.button.y,
+                    x_root: event.button.x_root,
+                    y_root: event.button.y_root,
+                    state: event.button.state,
+                };
+
+                if event.type_ == ButtonPress {
+                    WindowEvent::ButtonPress(button)
+                } else {
+                    WindowEvent::ButtonRelease(button)
+                }
+            }
+            MotionNotify => WindowEvent::MotionNotify(MotionEvent {
+                x: event.motion.x,
+                y: event.motion.y,
+                x_root: event.motion.x_root,
+                y_root: event.motion.y_root,
+                state: event.motion.state,
+            }),
+            EnterNotify | LeaveNotify => {
+                let crossing = CrossingEvent {
+                    x: event.crossing.x,
+                    y: event.crossing.y,
+                    x_root: event.crossing.x_root,
+                    y_root: event.crossing.y_root,
+                    state: event.crossing.state,
+                };
+
+                if event.type_ == EnterNotify {
+                    WindowEvent::EnterNotify(crossing)
+                } else {
+                    WindowEvent::LeaveNotify(crossing)
+                }
+            }
+            FocusIn => {
+                if let Some(ic) =
+                    _input_context(event.focus_change.display, event.focus_change.window)
+                {
+                    XSetICFocus(ic);
+                }
+                WindowEvent::FocusIn
+            }
+            FocusOut => {
+                if let Some(ic) =
+                    _input_context(event.focus_change.display, event.focus_change.window)
+                {
+                    XUnsetICFocus(ic);
+                }
+                WindowEvent::FocusOut
+            }
+            ConfigureNotify => {
+                _resize_back_buffer(
+                    event.configure.display,
+                    event.configure.window,
+                    event.configure.width as u32,
+                    event.configure.height as u32,
+                );
+                WindowEvent::ConfigureNotify {
+                    x: event.configure.x,
+                    y: event.configure.y,
+                    width: event.configure.width as u32,
+                    height: event.configure.height as u32,
+                    border_width: event.configure.border_width as u32,
+                }
+            }
+            MapNotify => WindowEvent::MapNotify,
+            UnmapNotify => WindowEvent::UnmapNotify,
+            DestroyNotify => WindowEvent::DestroyNotify,
+            ReparentNotify => WindowEvent::ReparentNotify {
+                parent: event.reparent.parent,
+                x: event.reparent.x,
+                y: event.reparent.y,
+            },
+            PropertyNotify => {
+                if _continue_incr_transfer(&event.property) {
+                    return None;
+                }
+
+                WindowEvent::PropertyNotify {
+                    atom: Atom(event.property.atom),
+                    deleted: event.property.state == PropertyDelete,
+                }
+            }
+            SelectionRequest => {
+                _serve_selection_request(&event.selection_request);
+                return None;
+            }
+            SelectionClear => {
+                let display = event.selection_clear.display;
+                SELECTIONS.lock().unwrap().retain(|owned| {
+                    !(owned.display == display as usize
+                        && owned.selection.0 == event.selection_clear.selection
+                        && owned.owner == event.selection_clear.window)
+                });
+
+                WindowEvent::SelectionLost(Atom(event.selection_clear.selection))
+            }
+            SelectionNotify => {
+                if let Some(event) = _handle_xdnd_paths(&event.selection) {
+                    return event;
+                }
+                WindowEvent::SelectionNotify {
+                    selection: Atom(event.selection.selection),
+                    target: Atom(event.selection.target),
+                    property: match event.selection.property {
+                        0 => None,
+                        property => Some(Atom(property)),
+                    },
+                }
+            }
+            ClientMessage => {
+                let display = event.client_message.display;
+                if let Some(event) = _handle_xdnd_message(&event.client_message) {
+                    return event;
+                }
+                if Atom(event.client_message.message_type) == _intern(display, "WM_PROTOCOLS") {
+                    let protocol = Atom(event.client_message.data.get_long(0) as c_ulong);
+                    if protocol == _intern(display, "WM_DELETE_WINDOW") {
+                        return Some(WindowEvent::CloseRequested);
+                    }
+                    if protocol == _intern(display, "_NET_WM_PING") {
+                        // Bounce the ping back to the root window so the WM knows we're alive
+                        let screen = _get_screen_number(display, event.client_message.window);
+                        let root = XRootWindow(display, screen);
+                        event.client_message.window = root;
+                        XSendEvent(
+                            display,
+                            root,
+                            False,
+                            (SubstructureNotifyMask | SubstructureRedirectMask) as c_long,
+                            event,
+                        );
+                        return None;
+                    }
+                }
+
+                let data = match event.client_message.format {
+                    8 => ClientMessageData::Byte(std::array::from_fn(|i| {
+                        event.client_message.data.get_byte(i) as u8
+                    })),
+                    16 => ClientMessageData::Short(std::array::from_fn(|i| {
+                        event.client_message.data.get_short(i)
+                    })),
+                    _ => ClientMessageData::Long(std::array::from_fn(|i| {
+                        event.client_message.data.get_long(i)
+                    })),
+                };
+
+                WindowEvent::ClientMessage(ClientMessageEvent {
+                    message_type: Atom(event.client_message.message_type),
+                    data,
+                })
+            }
+            VisibilityNotify => WindowEvent::VisibilityNotify(match event.visibility.state {
+                VisibilityUnobscured => Visibility::Unobscured,
+                VisibilityPartiallyObscured => Visibility::PartiallyObscured,
+                _ => Visibility::FullyObscured,
+            }),
+            _ => return None,
+        };
+
+        Some(event)
+    }
+}
+
+fn _flag_bits(flags: &[(bool, u32)]) -> u32 {
+    flags
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |bits, (_, bit)| bits | bit)
+}
+
+// Scales an 8-bit channel into the bits covered by a TrueColor visual mask
+fn _scale_channel(value: u8, mask: c_ulong) -> c_ulong {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let max = mask >> shift;
+    ((value as c_ulong * max + 127) / 255) << shift
 }
 
 fn _get_geometry(display: *mut x11::xlib::Display, window: c_ulong) -> Geometry {
@@ -920,3 +5683,176 @@ fn _get_geometry(display: *mut x11::xlib::Display, window: c_ulong) -> Geometry
         }
     }
 }
+
+// XOpenDisplay only tells us that it failed, so work out why by parsing the name
+// the same way Xlib does and probing the socket it would have used.
+fn _diagnose_open_failure(name: &str) -> DisplayError {
+    // An unreachable host can otherwise keep `Display::open` waiting for minutes
+    const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+    let reachable =
+        match _parse_display_name(name) {
+            None => return DisplayError::BadName(name.to_string()),
+            Some(DisplaySocket::Unix(path)) => UnixStream::connect(path).is_ok(),
+            Some(DisplaySocket::Tcp(host, port)) => (host.as_str(), port)
+                .to_socket_addrs()
+                .is_ok_and(|mut addrs| {
+                    addrs.any(|addr| TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).is_ok())
+                }),
+        };
+
+    if reachable {
+        DisplayError::AuthorizationFailed(name.to_string())
+    } else {
+        DisplayError::ConnectionRefused(name.to_string())
+    }
+}
+
+// Works out which socket a display name such as ":0", "host:1.0" or "unix:0" refers to
+fn _parse_display_name(name: &str) -> Option<DisplaySocket> {
+    // Only the display number matters here, not the screen
+    let number = |number: &str| number.split('.').next()?.parse::<u16>().ok();
+
+    // A full socket path, optionally followed by the display number
+    if name.starts_with('/') {
+        let path = match name.rsplit_once(':') {
+            Some((path, suffix)) if number(suffix).is_some() => path,
+            _ => name,
+        };
+        return Some(DisplaySocket::Unix(PathBuf::from(path)));
+    }
+
+    let (host, suffix) = name.rsplit_once(':')?;
+    let number = number(suffix)?;
+    // Strip the DECnet "host::0" separator and any "protocol/" prefix
+    let host = host.trim_end_matches(':');
+    let host = host.rsplit_once('/').map_or(host, |(_, host)| host);
+
+    Some(if host.is_empty() || host == "unix" {
+        DisplaySocket::Unix(PathBuf::from(format!("/tmp/.X11-unix/X{}", number)))
+    } else {
+        DisplaySocket::Tcp(host.to_string(), 6000u16.checked_add(number)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_display_name() {
+        let unix = |path: &str| Some(DisplaySocket::Unix(PathBuf::from(path)));
+        let tcp = |host: &str, port| Some(DisplaySocket::Tcp(host.to_string(), port));
+
+        assert_eq!(_parse_display_name(":0"), unix("/tmp/.X11-unix/X0"));
+        assert_eq!(_parse_display_name(":0.1"), unix("/tmp/.X11-unix/X0"));
+        assert_eq!(_parse_display_name("unix:0"), unix("/tmp/.X11-unix/X0"));
+        assert_eq!(_parse_display_name("host:1"), tcp("host", 6001));
+        assert_eq!(_parse_display_name("tcp/host:1.2"), tcp("host", 6001));
+        assert_eq!(_parse_display_name("host::3"), tcp("host", 6003));
+        assert_eq!(
+            _parse_display_name("/path/to/socket"),
+            unix("/path/to/socket")
+        );
+        assert_eq!(
+            _parse_display_name("/path/to/socket:0"),
+            unix("/path/to/socket")
+        );
+    }
+
+    #[test]
+    fn parse_bad_display_name() {
+        assert_eq!(_parse_display_name(""), None);
+        assert_eq!(_parse_display_name("host"), None);
+        assert_eq!(_parse_display_name(":x"), None);
+        assert_eq!(_parse_display_name("host:"), None);
+        assert_eq!(_parse_display_name("host:65535"), None);
+    }
+
+    #[test]
+    fn property_data_append() {
+        let joined =
+            PropertyData::Format8(b"ab".to_vec()).append(PropertyData::Format8(b"c".to_vec()));
+        assert_eq!(joined, PropertyData::Format8(b"abc".to_vec()));
+
+        let joined = PropertyData::Format32(vec![1, 2]).append(PropertyData::Format32(vec![]));
+        assert_eq!(joined, PropertyData::Format32(vec![1, 2]));
+        assert_eq!(joined.len(), 2);
+
+        // A property whose format changed between chunks keeps the newer chunk
+        let joined = PropertyData::Format8(b"ab".to_vec()).append(PropertyData::Format16(vec![7]));
+        assert_eq!(joined, PropertyData::Format16(vec![7]));
+    }
+
+    #[test]
+    fn parse_uri_list() {
+        let list = "# dragged from a file manager\r\n\
+                    file:///tmp/a%20b.txt\r\n\
+                    http://example.com/index.html\r\n\
+                    file:///tmp/%C3%A4%E2%82%AC\r\n\
+                    \r\n\
+                    file://localhost/tmp/local\r\n\
+                    file://elsewhere.invalid/tmp/remote\r\n\
+                    file:///tmp/100%25\r\n";
+        assert_eq!(
+            _parse_uri_list(list),
+            [
+                "/tmp/a b.txt",
+                "/tmp/\u{e4}\u{20ac}",
+                "/tmp/local",
+                "/tmp/100%"
+            ]
+            .map(PathBuf::from)
+        );
+
+        // Bare newlines work too, and a stray '%' stays as it is
+        assert_eq!(
+            _parse_uri_list("file:///a%zz\nfile:///b"),
+            [PathBuf::from("/a%zz"), PathBuf::from("/b")]
+        );
+        assert!(_parse_uri_list("file://host-only\n#file:///commented").is_empty());
+    }
+
+    #[test]
+    fn percent_encode() {
+        assert_eq!(_percent_encode(Path::new("/tmp/a b#c")), "/tmp/a%20b%23c");
+        assert_eq!(_percent_encode(Path::new("/tmp/\u{e4}")), "/tmp/%C3%A4");
+        assert_eq!(_percent_encode(Path::new("/a-b_c.d~e")), "/a-b_c.d~e");
+
+        let paths = [
+            "/tmp/plain",
+            "/tmp/with space/and#hash",
+            "/tmp/\u{e4}\u{f6}\u{fc}/\u{65e5}\u{672c}",
+            "/tmp/100%/?&=+",
+        ];
+        let list: String = paths
+            .iter()
+            .map(|path| format!("file://{}\r\n", _percent_encode(Path::new(path))))
+            .collect();
+        assert_eq!(_parse_uri_list(&list), paths.map(PathBuf::from));
+    }
+
+    #[test]
+    fn modifiers() {
+        // Button state bits are dropped
+        let state = ShiftMask | ControlMask | Mod4Mask | Button1Mask;
+        let modifiers = Modifiers::from_state(state);
+        assert_eq!(
+            modifiers,
+            Modifiers::SHIFT | Modifiers::CONTROL | Modifiers::SUPER
+        );
+        assert_eq!(modifiers.bits(), ShiftMask | ControlMask | Mod4Mask);
+
+        assert!(modifiers.contains(Modifiers::SHIFT | Modifiers::SUPER));
+        assert!(!modifiers.contains(Modifiers::SHIFT | Modifiers::ALT));
+        assert!(modifiers.contains(Modifiers::empty()));
+        assert_eq!(modifiers & Modifiers::CONTROL, Modifiers::CONTROL);
+        assert!((modifiers & Modifiers::NUM_LOCK).is_empty());
+
+        assert_eq!(Modifiers::from_state(Button3Mask), Modifiers::empty());
+        assert_eq!(Modifiers::default(), Modifiers::empty());
+        assert_eq!(Modifiers::from_state(Mod1Mask), Modifiers::ALT);
+        assert_eq!(Modifiers::from_state(Mod2Mask), Modifiers::NUM_LOCK);
+        assert_eq!(Modifiers::from_state(LockMask), Modifiers::CAPS_LOCK);
+    }
+}